@@ -0,0 +1,389 @@
+//! An epmd server for running on an existing tokio runtime.
+//!
+//! This serves the same protocol as `epmd::run`, with requests handled by
+//! the same `EpmdCore`; only the I/O is done with tokio. Each connection
+//! gets a task of its own, and a node stays registered for as long as the
+//! task holding the connection it registered on is alive.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+
+use crate::constants::INBUF_SIZE;
+use crate::config::EpmdConfig;
+use crate::core::{Action, ConnId, Epmd, EpmdCore};
+use crate::epmd;
+use crate::error::EpmdError;
+
+/// The state shared by the accept loops and every connection's task
+struct Shared {
+    core: Mutex<EpmdCore>,
+    shutdown: watch::Sender<bool>,
+    // Tells a connection's task that its node was stopped
+    stopped: Mutex<HashMap<ConnId, Arc<Notify>>>,
+    // Never reused, unlike fds; a `Close` for a connection which has gone
+    // can't reach one accepted after it.
+    next_id: AtomicI32,
+}
+
+impl Shared {
+    fn core(&self) -> MutexGuard<'_, EpmdCore> {
+        // A task that panicked while holding the lock can't have left the
+        // registry half updated, so carry on with it.
+        self.core.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn stopped(&self) -> MutexGuard<'_, HashMap<ConnId, Arc<Notify>>> {
+        self.stopped.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An epmd bound to its listening sockets, but not yet serving on them
+pub struct AsyncServer {
+    listeners: Vec<TcpListener>,
+    shared: Arc<Shared>,
+}
+
+impl AsyncServer {
+    /// Binds the listening sockets `config` asks for. This has to be
+    /// called from within a tokio runtime.
+    pub fn bind(
+        mut epmd: Epmd,
+        config: EpmdConfig,
+    ) -> Result<AsyncServer, EpmdError> {
+        epmd.log = epmd::logger(&config);
+        let mut listeners = Vec::new();
+        for listener in epmd::listen_sockets(&config)? {
+            listener.set_nonblocking(true)?;
+            listeners.push(TcpListener::from_std(listener)?);
+        }
+        let (shutdown, _) = watch::channel(false);
+        let stopped = Mutex::new(HashMap::new());
+        let core = Mutex::new(EpmdCore::new(epmd, config));
+        let next_id = AtomicI32::new(0);
+        let shared = Shared { core, shutdown, stopped, next_id };
+        Ok(AsyncServer { listeners, shared: Arc::new(shared) })
+    }
+
+    /// The addresses we're listening on
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Starts serving on the current runtime
+    pub fn spawn(self) -> ShutdownHandle {
+        let local_addrs = self.local_addrs();
+        let shutdown = self.shared.shutdown.clone();
+        let task = tokio::spawn(self.serve());
+        ShutdownHandle { local_addrs, shutdown, task }
+    }
+
+    /// Serves until we're killed; every connection is closed by the time
+    /// this returns.
+    pub async fn serve(self) {
+        {
+            let core = self.shared.core();
+            info!(core.epmd.log, "epmd running - daemon = {}",
+                  core.config.is_daemon);
+        }
+        let mut accepting = JoinSet::new();
+        for listener in self.listeners {
+            accepting.spawn(accept_loop(listener, self.shared.clone()));
+        }
+        while accepting.join_next().await.is_some() {}
+    }
+}
+
+/// Stops a server started with `AsyncServer::spawn`
+pub struct ShutdownHandle {
+    local_addrs: Vec<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ShutdownHandle {
+    /// The addresses the server is listening on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// `true` once the server has stopped, e.g. after being killed
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stops the server, closing every connection, and waits until it has
+    pub async fn shutdown(self) {
+        self.shutdown.send_replace(true);
+        let _ = self.task.await;
+    }
+}
+
+async fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    let mut shutdown = shared.shutdown.subscribe();
+    let mut conns = JoinSet::new();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, peer_addr)) => {
+                    conns.spawn(handle_conn(stream, peer_addr, shared.clone()));
+                }
+                Err(err) => {
+                    // Most likely out of file descriptors; back off for a
+                    // moment rather than spin.
+                    error!(shared.core().epmd.log, "accept() failed: {}", err);
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            },
+            // Reap the tasks of connections that have finished
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            _ = stopping(&mut shutdown) => break,
+        }
+    }
+    drop(listener);
+    while conns.join_next().await.is_some() {}
+}
+
+fn secs(secs: usize) -> Duration {
+    Duration::from_secs(secs as u64)
+}
+
+/// Waits until the server is told to shut down
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    // An error means the server is gone, which is just as final
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+async fn handle_conn(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    shared: Arc<Shared>,
+) {
+    // tokio has no way to wait for a connection without accepting it, so
+    // `-delay_accept` holds up serving it instead; to the client it's the
+    // same, no reply until the delay is over.
+    let mut shutdown = shared.shutdown.subscribe();
+    let delay_accept = secs(shared.core().config.delay_accept);
+    tokio::select! {
+        () = time::sleep(delay_accept) => {}
+        _ = stopping(&mut shutdown) => return,
+    }
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let local_addr = match stream.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(err) => {
+            error!(shared.core().epmd.log, "{} (conn {}): connection setup \
+                                            failed: {}", peer_addr, id, err);
+            return;
+        }
+    };
+    debug!(shared.core().epmd.log, "{} (conn {}): accepted", peer_addr, id);
+    let (timeout, delay_write) = {
+        let mut core = shared.core();
+        core.on_connect(id, peer_addr, local_addr);
+        (core.config.packet_timeout, secs(core.config.delay_write))
+    };
+
+    // Whoever stops our node can't close the connection themselves
+    let stopped = Arc::new(Notify::new());
+    shared.stopped().insert(id, stopped.clone());
+    let mut buf = [0; INBUF_SIZE];
+    let mut keep = false;
+    'conn: loop {
+        let read = async {
+            if keep {
+                // Registered nodes stay connected for as long as they like
+                stream.read(&mut buf).await
+            } else {
+                time::timeout(timeout, stream.read(&mut buf)).await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            }
+        };
+        let bytes_recv = tokio::select! {
+            res = read => match res {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) => {
+                    debug!(shared.core().epmd.log,
+                           "{} (conn {}): read() failed: {}",
+                           peer_addr, id, err);
+                    break;
+                }
+            },
+            () = stopped.notified() => break,
+            _ = stopping(&mut shutdown) => break,
+        };
+
+        let actions = {
+            let mut core = shared.core();
+            let actions = core.on_data(id, &buf[..bytes_recv]);
+            if core.is_shutdown() {
+                shared.shutdown.send_replace(true);
+            }
+            actions
+        };
+        for action in actions {
+            match action {
+                Action::Write(_, data) => {
+                    // `-delay_write` only holds up this connection
+                    if !delay_write.is_zero() {
+                        time::sleep(delay_write).await;
+                    }
+                    if let Err(err) = stream.write_all(&data).await {
+                        debug!(shared.core().epmd.log,
+                               "{} (conn {}): write() failed: {}",
+                               peer_addr, id, err);
+                        break 'conn;
+                    }
+                }
+                Action::Close(other) if other == id => break 'conn,
+                // A stopped node's connection, owned by another task
+                Action::Close(other) => {
+                    if let Some(stopped) = shared.stopped().get(&other) {
+                        // Remembered if that task isn't waiting just now
+                        stopped.notify_one();
+                    }
+                }
+                Action::KeepAlive(_) => keep = true,
+            }
+        }
+    }
+    // A node's registration goes with the connection it was made on
+    shared.stopped().remove(&id);
+    let mut core = shared.core();
+    debug!(core.epmd.log, "{} (conn {}): connection closed", peer_addr, id);
+    core.on_close(id);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tokio::task::spawn_blocking;
+
+    use crate::client::EpmdClient;
+    use crate::config::EpmdConfig;
+    use crate::core::Epmd;
+    use crate::test_util::node;
+
+    use super::AsyncServer;
+
+    fn server(relaxed: bool) -> (AsyncServer, EpmdClient) {
+        let config = EpmdConfig {
+            address: "127.0.0.1".into(),
+            port: 0,
+            brutal_kill: relaxed,
+            ..EpmdConfig::new()
+        };
+        let server = AsyncServer::bind(Epmd::new(), config).unwrap();
+        let addrs: Vec<SocketAddr> = server.local_addrs();
+        let client = EpmdClient { port: addrs[0].port(), ..EpmdClient::new() };
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn test_register_and_shutdown () {
+        let (server, client) = server(false);
+        let handle = server.spawn();
+
+        let names = spawn_blocking(move || {
+            let reg = client.register(&node("foo", 5555)).unwrap();
+            assert_eq!(client.port_please("foo").unwrap().unwrap().port, 5555);
+            let names = client.names().unwrap();
+            // Dropping the registration closes its connection, which
+            // unregisters the node.
+            drop(reg);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while client.port_please("foo").unwrap().is_some() {
+                assert!(Instant::now() < deadline, "foo is still registered");
+                thread::sleep(Duration::from_millis(10));
+            }
+            names
+        }).await.unwrap();
+        assert_eq!(names, vec![("foo".to_string(), 5555)]);
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_stop_and_kill () {
+        let (server, client) = server(true);
+        let handle = server.spawn();
+
+        spawn_blocking(move || {
+            let reg = client.register(&node("foo", 5555)).unwrap();
+            assert!(client.stop("foo").unwrap());
+            assert!(client.port_please("foo").unwrap().is_none());
+            // Stopping a node closes the connection it registered on
+            let mut buf = [0; 1];
+            assert_eq!((&mut reg.stream()).read(&mut buf).unwrap(), 0);
+            assert!(client.kill().unwrap());
+        }).await.unwrap();
+        handle.task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_many_nodes () {
+        let (server, client) = server(true);
+        let handle = server.spawn();
+
+        spawn_blocking(move || {
+            let regs: Vec<_> = (0..40)
+                .map(|i| client.register(&node(&format!("n{}", i), 5555)).unwrap())
+                .collect();
+            for i in 0..40 {
+                assert!(client.stop(&format!("n{}", i)).unwrap());
+            }
+            // Every one of their connections is closed
+            for reg in regs {
+                let mut buf = [0; 1];
+                assert_eq!((&mut reg.stream()).read(&mut buf).unwrap(), 0);
+            }
+        }).await.unwrap();
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_delay_write () {
+        let config = EpmdConfig {
+            address: "127.0.0.1".into(),
+            port: 0,
+            delay_write: 1,
+            ..EpmdConfig::new()
+        };
+        let server = AsyncServer::bind(Epmd::new(), config).unwrap();
+        let port = server.local_addrs()[0].port();
+        let handle = server.spawn();
+
+        let start = Instant::now();
+        let names: Vec<_> = (0..2)
+            .map(|_| {
+                let client = EpmdClient { port, ..EpmdClient::new() };
+                spawn_blocking(move || client.names().unwrap())
+            })
+            .collect();
+        for names in names {
+            assert_eq!(names.await.unwrap(), vec![]);
+        }
+        // Both replies were held back at the same time
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1900), "{:?}", elapsed);
+        handle.shutdown().await;
+    }
+}
@@ -1,11 +1,9 @@
 extern crate re_epmd as epmd;
 
 use epmd::{parse_args, display_usage};
-use epmd::{run_console, run_daemon};
-use epmd::{
-    ParseResponse as PR,
-    //EpmdReq as REQ
-};
+use epmd::{run_console, run_daemon, run_call};
+use epmd::ParseResponse as PR;
+use std::process::exit;
 
 fn main () {
     let epmd = epmd::Epmd::new();
@@ -15,27 +13,20 @@ fn main () {
         check_wsa_version();
     }
 
-    //let with_request: Option<REQ> = None;
     match parse_args(&mut config) {
         PR::Ok         => {},
-        PR::ShouldExit => { return; },
-        PR::BadOpt     => { display_usage(); return; },
-        PR::Call(_req)  => { /*with_request = Some(req)*/ }
+        PR::BadOpt     => { display_usage(); exit(1); },
+        PR::Call(req)  => { exit(run_call(&config, req)); }
     }
 
-    /* TODO: Check max file descriptors for system
-    See the note @ constants::MAX_FILE_DESCRIPTORS;
-     */
-
-    // TODO:
-    // When the users wants to have a call, such as kill or so, the
-    // client needs to connect with the existing epmd daemon and send
-    // the command packet to the epmd and process the response and exit
-
-    if config.is_daemon {
-        run_daemon(epmd, config);
+    let result = if config.is_daemon {
+        run_daemon(epmd, config)
     } else {
-        run_console(epmd, config);
+        run_console(epmd, config)
+    };
+    if let Err(err) = result {
+        eprintln!("epmd: {}", err);
+        exit(1);
     }
 }
 
@@ -0,0 +1,333 @@
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::codec::{encode_request, decode_response};
+use crate::codec::{EpmdReq, EpmdResp};
+use crate::config::EpmdConfig;
+use crate::socket::get_port_number;
+
+/// A client for talking to a running epmd.
+///
+/// Every call opens a new connection to epmd, as the protocol expects; only
+/// a node registration keeps its connection open, see `EpmdClient::register`.
+#[derive(Debug, Clone)]
+pub struct EpmdClient {
+    pub host: String,
+    pub port: u16,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+}
+
+/// What epmd knows about a registered node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub name: String,
+    pub port: u16,
+    pub node_type: u8,     // 77 = normal erlang node; 72 = hidden (c-node)
+    pub protocol: u8,      // 0 = tcp/ipv4
+    pub high_version: u16,
+    pub low_version: u16,
+    pub extra: Vec<u8>,
+}
+
+/// A node registration with epmd; the name stays registered for as long as
+/// this is alive and is unregistered once it's dropped.
+#[derive(Debug)]
+pub struct Registration {
+    stream: TcpStream,
+    creation: u32,
+}
+
+impl Registration {
+    /// The creation epmd handed out for this incarnation of the node
+    pub fn creation(&self) -> u32 {
+        self.creation
+    }
+
+    /// The connection holding the registration open
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl EpmdClient {
+    /// A client for the epmd on this machine, on `ERL_EPMD_PORT` or 4369.
+    pub fn new() -> EpmdClient {
+        EpmdClient {
+            host: "localhost".into(),
+            port: get_port_number(),
+            connect_timeout: Duration::new(5, 0),
+            read_timeout: Duration::new(5, 0),
+        }
+    }
+
+    /// The names and ports of all nodes registered with epmd
+    pub fn names(&self) -> io::Result<Vec<(String, u16)>> {
+        match self.request(EpmdReq::Names)? {
+            EpmdResp::Names(_, names) => {
+                Ok(names.lines().filter_map(parse_name_line).collect())
+            }
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Looks up the node registered as `name`; `None` if there isn't one
+    pub fn port_please(&self, name: &str) -> io::Result<Option<NodeInfo>> {
+        match self.request(EpmdReq::Port2(name.into()))? {
+            EpmdResp::Port2Ok(_, port, node_type, protocol, high_version,
+                              low_version, name, extra) => {
+                Ok(Some(NodeInfo {
+                    name, port, node_type, protocol, high_version,
+                    low_version, extra,
+                }))
+            }
+            EpmdResp::Port2Err(_) => Ok(None),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Registers `node` with epmd
+    pub fn register(&self, node: &NodeInfo) -> io::Result<Registration> {
+        let req = EpmdReq::Alive2(node.port, node.node_type, node.protocol,
+                                  node.high_version, node.low_version,
+                                  node.name.clone(), node.extra.clone());
+        let mut stream = self.send(&req)?;
+        // The connection stays open, so only read as much as the reply needs
+        let mut data = read_upto(&mut stream, 2)?;
+        let rest = match data.first() {
+            Some(&b'v') => 4, // ALIVE2_X_RESP; 32-bit creation
+            _ => 2,           // ALIVE2_RESP;   16-bit creation
+        };
+        data.append(&mut read_upto(&mut stream, rest)?);
+        let (result, creation) = match decode_response(&req, &data)? {
+            EpmdResp::Alive2(result, creation) => (result, creation as u32),
+            EpmdResp::Alive2X(result, creation) => (result, creation),
+            resp => return Err(unexpected(resp)),
+        };
+        if result != 0 {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("epmd refused to register {}", node.name)));
+        }
+        let _ = stream.set_read_timeout(None);
+        Ok(Registration { stream, creation })
+    }
+
+    /// Asks epmd to exit; `false` if it refused as nodes are still alive
+    pub fn kill(&self) -> io::Result<bool> {
+        match self.request(EpmdReq::Kill)? {
+            EpmdResp::KillOk(_) => Ok(true),
+            EpmdResp::KillErr(_) => Ok(false),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Forcibly unregisters `name`; `false` if no such node was registered.
+    /// Only allowed when epmd runs with relaxed command checking.
+    pub fn stop(&self, name: &str) -> io::Result<bool> {
+        match self.request(EpmdReq::Stop(name.into()))? {
+            EpmdResp::StopOk(_) => Ok(true),
+            EpmdResp::StopErr(_) => Ok(false),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// The text of epmd's node dump
+    pub fn dump(&self) -> io::Result<String> {
+        match self.request(EpmdReq::Dump)? {
+            EpmdResp::Dump(_, dump) => Ok(dump),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Sends `req` and parses the reply epmd sends before closing; ALIVE2
+    /// registrations never close, so use `EpmdClient::register` for those.
+    pub fn request(&self, req: EpmdReq) -> io::Result<EpmdResp> {
+        let mut stream = self.send(&req)?;
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data)?;
+        Ok(decode_response(&req, &data)?)
+    }
+
+    /// Connects to epmd and sends off `req`
+    pub fn send(&self, req: &EpmdReq) -> io::Result<TcpStream> {
+        let mesg = encode_request(req)?;
+        let mut stream = self.connect()?;
+        stream.write_all(&mesg)?;
+        Ok(stream)
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?;
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.read_timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, "no address for epmd host")
+        }))
+    }
+}
+
+impl Default for EpmdClient {
+    fn default() -> EpmdClient {
+        EpmdClient::new()
+    }
+}
+
+/// Sends `req` to the epmd running on this machine, prints its reply the way
+/// the upstream `epmd` client does, and returns the exit status to use.
+pub fn call(config: &EpmdConfig, req: EpmdReq) -> i32 {
+    let client = EpmdClient {
+        host: if config.use_ipv6 { "::1".into() } else { "127.0.0.1".into() },
+        port: config.port,
+        read_timeout: config.packet_timeout,
+        ..EpmdClient::new()
+    };
+
+    let mesg = match encode_request(&req) {
+        Ok(mesg) => mesg,
+        Err(err) => {
+            eprintln!("epmd: {}", err);
+            return 1;
+        }
+    };
+    let mut stream = match client.connect() {
+        Ok(stream) => stream,
+        Err(_) => {
+            if !config.silent {
+                eprintln!("epmd: Cannot connect to local epmd");
+            }
+            return 1;
+        }
+    };
+    if stream.write_all(&mesg).is_err() {
+        println!("epmd: Can't write to epmd");
+        return 1;
+    }
+
+    match req {
+        EpmdReq::Names | EpmdReq::Dump => print_node_info(config, stream),
+        EpmdReq::Kill => {
+            match read_upto(&mut stream, 2) {
+                Ok(ref reply) if reply == b"OK" => {
+                    println!("Killed");
+                    0
+                }
+                Ok(ref reply) if reply.len() == 2 => {
+                    println!("Killing not allowed - living nodes in database.");
+                    0
+                }
+                reply => print_bad_reply(reply),
+            }
+        }
+        EpmdReq::Stop(_) => {
+            match read_upto(&mut stream, 7) {
+                Ok(ref reply) if reply.len() == 7 => {
+                    println!("{}", String::from_utf8_lossy(reply));
+                    0
+                }
+                reply => print_bad_reply(reply),
+            }
+        }
+        EpmdReq::Alive2(..) | EpmdReq::Port2(_) => 0,
+    }
+}
+
+/// Prints the reply to a NAMES or DUMP request; the epmd port followed by
+/// whatever text epmd sends until it closes the connection.
+fn print_node_info(config: &EpmdConfig, mut stream: TcpStream) -> i32 {
+    let port = match read_upto(&mut stream, 4) {
+        Ok(ref port) if port.len() == 4 => {
+            u32::from_be_bytes([port[0], port[1], port[2], port[3]])
+        }
+        _ => {
+            if !config.silent {
+                println!("epmd: no response from local epmd");
+            }
+            return 1;
+        }
+    };
+    let mut data = Vec::new();
+    let _ = stream.read_to_end(&mut data);
+    if !config.silent {
+        println!("epmd: up and running on port {} with data:", port);
+        let stdout = io::stdout();
+        let _ = stdout.lock().write_all(&data);
+    }
+    0
+}
+
+fn print_bad_reply(reply: io::Result<Vec<u8>>) -> i32 {
+    match reply {
+        Ok(reply) => {
+            println!("epmd: local epmd responded with <{}>",
+                     String::from_utf8_lossy(&reply));
+        }
+        Err(_) => println!("epmd: failed to read answer from local epmd"),
+    }
+    1
+}
+
+/// Reads up to `len` bytes, stopping early if epmd closes the connection
+fn read_upto(stream: &mut TcpStream, len: usize) -> io::Result<Vec<u8>> {
+    let mut reply = Vec::with_capacity(len);
+    Read::by_ref(stream).take(len as u64).read_to_end(&mut reply)?;
+    Ok(reply)
+}
+
+/// Parses a "name <name> at port <port>" line of a NAMES reply
+fn parse_name_line(line: &str) -> Option<(String, u16)> {
+    let rest = line.strip_prefix("name ")?;
+    let at = rest.rfind(" at port ")?;
+    let port = rest[at + " at port ".len()..].trim().parse().ok()?;
+    Some((rest[..at].to_string(), port))
+}
+
+fn unexpected(resp: EpmdResp) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData,
+                   format!("unexpected reply from epmd: {:?}", resp))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::server::EpmdServer;
+    use crate::test_util;
+
+    use super::parse_name_line;
+
+    #[test]
+    fn test_client_round_trip () {
+        let server = EpmdServer::builder().relaxed(true).spawn().unwrap();
+        let client = server.client();
+        let node = test_util::node("foo", 5555);
+        let reg = client.register(&node).unwrap();
+        assert!(reg.creation() != 0);
+        assert!(client.register(&node).is_err());
+        assert_eq!(client.names().unwrap(), vec![("foo".into(), 5555)]);
+        assert_eq!(client.port_please("foo").unwrap(), Some(node));
+        assert_eq!(client.port_please("bar").unwrap(), None);
+        assert!(client.dump().unwrap().contains("<foo> at port 5555"));
+        assert!(client.stop("foo").unwrap());
+        assert!(!client.stop("foo").unwrap());
+        assert!(client.kill().unwrap());
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_parse_name_line () {
+        assert_eq!(parse_name_line("name foo at port 5555"),
+                   Some(("foo".into(), 5555)));
+        assert_eq!(parse_name_line("name a at port b at port 1"),
+                   Some(("a at port b".into(), 1)));
+        assert_eq!(parse_name_line("active name <foo>"), None);
+    }
+}
@@ -0,0 +1,491 @@
+//! The epmd wire protocol.
+//!
+//! Requests are sent with a 2-byte big-endian length prefix followed by the
+//! request code and its data. Responses carry no length prefix; their size
+//! follows from the request they answer, or epmd closes the connection once
+//! the response is complete. This module is shared by the server and the
+//! client so both agree on a single definition of the protocol.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::from_utf8;
+
+use crate::constants::{
+    ALIVE2_REQ, PORT2_REQ, NAMES_REQ, DUMP_REQ, KILL_REQ, STOP_REQ,
+    ALIVE2_RESP, ALIVE2_X_RESP, PORT2_RESP, MAX_SYM_LEN, INBUF_SIZE,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EpmdReq {
+    // port, type, protocol, high_ver, low_ver, name, extra
+    Alive2(u16, u8, u8, u16, u16, String, Vec<u8>),
+    Port2(String), // Name
+    Names,
+    Dump,
+    Kill,
+    Stop(String) // Name
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EpmdResp {
+    None,
+    Alive2(u8, u16), // Result, Creation
+    Alive2X(u8, u32), // Result, Creation; for OTP-23 and newer nodes
+    Port2Err(u8),    // just result is given if error.
+    // result, port, type, protocol, high_ver, low_ver, name, extra
+    Port2Ok(u8, u16, u8, u8, u16, u16, String, Vec<u8>),
+    Names(u32, String),
+    Dump(u32, String),
+    KillErr(String), // "NO" is sent if there are still living nodes
+    KillOk(String),  // "OK" is sent if successful
+    StopErr(String), // "NOEXIST" is sent if node doesn't exist
+    StopOk(String),  // "STOPPED" is sent if node is removed
+}
+
+/// Reasons a message couldn't be decoded
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The message ended before all of its fields were read
+    Truncated,
+    /// The length prefix doesn't match the size of the request
+    BadLength(usize),
+    /// The request or response code isn't one we know about
+    UnknownOpcode(u8),
+    /// A node name, or other text, isn't valid UTF-8
+    InvalidName,
+    /// A field is longer than the protocol allows
+    Oversized(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::BadLength(len) => {
+                write!(f, "bad length prefix: {}", len)
+            }
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode: {}", op),
+            DecodeError::InvalidName => write!(f, "name is not valid UTF-8"),
+            DecodeError::Oversized(len) => {
+                write!(f, "field of {} bytes is too long", len)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(err: DecodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Reasons a request couldn't be encoded
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EncodeError {
+    /// A name, or the request as a whole, is longer than epmd accepts
+    Oversized(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::Oversized(len) => {
+                write!(f, "field of {} bytes is too long", len)
+            }
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+impl From<EncodeError> for io::Error {
+    fn from(err: EncodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Encodes a request, including its length prefix, as a client sends it.
+/// Names and requests which epmd would reject as too long are refused.
+pub fn encode_request(req: &EpmdReq) -> Result<Vec<u8>, EncodeError> {
+    match *req {
+        EpmdReq::Alive2(_, _, _, _, _, ref name, _) |
+        EpmdReq::Port2(ref name) |
+        EpmdReq::Stop(ref name) if name.len() > MAX_SYM_LEN => {
+            return Err(EncodeError::Oversized(name.len()));
+        }
+        _ => {}
+    }
+    let mut data = match *req {
+        EpmdReq::Alive2(port, n_type, proto, hver, lver, ref name, ref ext) => {
+            let mut data = vec![ALIVE2_REQ];
+            data.extend_from_slice(&port.to_be_bytes());
+            data.push(n_type);
+            data.push(proto);
+            data.extend_from_slice(&hver.to_be_bytes());
+            data.extend_from_slice(&lver.to_be_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+            data.extend_from_slice(ext);
+            data
+        }
+        EpmdReq::Port2(ref name) => {
+            let mut data = vec![PORT2_REQ];
+            data.extend_from_slice(name.as_bytes());
+            data
+        }
+        EpmdReq::Names => vec![NAMES_REQ],
+        EpmdReq::Dump => vec![DUMP_REQ],
+        EpmdReq::Kill => vec![KILL_REQ],
+        EpmdReq::Stop(ref name) => {
+            let mut data = vec![STOP_REQ];
+            data.extend_from_slice(name.as_bytes());
+            data
+        }
+    };
+    // Which also keeps every length within a u16
+    if data.len() > INBUF_SIZE {
+        return Err(EncodeError::Oversized(data.len()));
+    }
+    let mut mesg = (data.len() as u16).to_be_bytes().to_vec();
+    mesg.append(&mut data);
+    Ok(mesg)
+}
+
+/// The size of the request at the start of `buf`, length prefix included;
+/// `None` until enough of `buf` has arrived to tell. Requests declaring an
+/// empty or oversized body are rejected before any more of them is read.
+pub fn request_len(buf: &[u8]) -> Result<Option<usize>, DecodeError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if len == 0 || len > INBUF_SIZE {
+        return Err(DecodeError::BadLength(len));
+    }
+    Ok(Some(len + 2))
+}
+
+/// Decodes a single request, including its length prefix
+pub fn decode_request(mesg: &[u8]) -> Result<EpmdReq, DecodeError> {
+    let mut mesg = Reader::new(mesg);
+    let len = mesg.u16()? as usize;
+    if len == 0 || len > INBUF_SIZE {
+        return Err(DecodeError::BadLength(len));
+    }
+    if mesg.remaining() < len {
+        return Err(DecodeError::Truncated);
+    }
+    if mesg.remaining() > len {
+        return Err(DecodeError::BadLength(len));
+    }
+
+    match mesg.u8()? {
+        ALIVE2_REQ => {
+            let port      = mesg.u16()?;
+            let node_type = mesg.u8()?;
+            let protocol  = mesg.u8()?;
+            let high_ver  = mesg.u16()?;
+            let low_ver   = mesg.u16()?;
+            let name_len  = mesg.u16()? as usize;
+            let name      = mesg.name(name_len)?;
+            let extra_len = mesg.u16()? as usize;
+            let extra     = mesg.bytes(extra_len)?.to_vec();
+            if mesg.remaining() > 0 {
+                return Err(DecodeError::BadLength(len));
+            }
+            Ok(EpmdReq::Alive2(port, node_type, protocol, high_ver, low_ver,
+                               name, extra))
+        }
+        PORT2_REQ => {
+            let name_len = mesg.remaining();
+            Ok(EpmdReq::Port2(mesg.name(name_len)?))
+        }
+        NAMES_REQ => Ok(EpmdReq::Names),
+        DUMP_REQ  => Ok(EpmdReq::Dump),
+        KILL_REQ  => Ok(EpmdReq::Kill),
+        STOP_REQ  => {
+            let name_len = mesg.remaining();
+            Ok(EpmdReq::Stop(mesg.name(name_len)?))
+        }
+        op => Err(DecodeError::UnknownOpcode(op)),
+    }
+}
+
+/// Encodes a response as epmd sends it
+pub fn encode_response(resp: &EpmdResp) -> Vec<u8> {
+    match *resp {
+        EpmdResp::None => vec![],
+        EpmdResp::Alive2(result, creation) => {
+            let mut resp = vec![ALIVE2_RESP, result];
+            resp.extend_from_slice(&creation.to_be_bytes());
+            resp
+        }
+        EpmdResp::Alive2X(result, creation) => {
+            let mut resp = vec![ALIVE2_X_RESP, result];
+            resp.extend_from_slice(&creation.to_be_bytes());
+            resp
+        }
+        EpmdResp::Port2Err(errno) => {
+            vec![PORT2_RESP, errno]
+        }
+        EpmdResp::Port2Ok(res, port, n_type, proto, hver, lver,
+                          ref name, ref ext) => {
+            // Only ever nodes which registered with a request we decoded
+            debug_assert!(name.len() <= MAX_SYM_LEN);
+            debug_assert!(ext.len() <= u16::MAX as usize);
+            let mut resp = vec![PORT2_RESP, res];
+            resp.extend_from_slice(&port.to_be_bytes());
+            resp.push(n_type);
+            resp.push(proto);
+            resp.extend_from_slice(&hver.to_be_bytes());
+            resp.extend_from_slice(&lver.to_be_bytes());
+            resp.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            resp.extend_from_slice(name.as_bytes());
+            resp.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+            resp.extend_from_slice(ext);
+            resp
+        }
+        EpmdResp::Names(epmd_port, ref name_list) |
+        EpmdResp::Dump(epmd_port, ref name_list) => {
+            let mut resp = epmd_port.to_be_bytes().to_vec();
+            resp.extend_from_slice(name_list.as_bytes());
+            resp
+        }
+        EpmdResp::KillErr(_) => b"NO".to_vec(),
+        EpmdResp::KillOk(_)  => b"OK".to_vec(),
+        EpmdResp::StopErr(_) => b"NOEXIST".to_vec(),
+        EpmdResp::StopOk(_)  => b"STOPPED".to_vec(),
+    }
+}
+
+/// Decodes the complete response epmd sent for `req`
+pub fn decode_response(
+    req: &EpmdReq,
+    data: &[u8]
+) -> Result<EpmdResp, DecodeError> {
+    let mut resp = Reader::new(data);
+    match *req {
+        EpmdReq::Alive2(..) => {
+            match resp.u8()? {
+                ALIVE2_RESP => Ok(EpmdResp::Alive2(resp.u8()?, resp.u16()?)),
+                ALIVE2_X_RESP => Ok(EpmdResp::Alive2X(resp.u8()?, resp.u32()?)),
+                op => Err(DecodeError::UnknownOpcode(op)),
+            }
+        }
+        EpmdReq::Port2(_) => {
+            match resp.u8()? {
+                PORT2_RESP => {},
+                op => return Err(DecodeError::UnknownOpcode(op)),
+            }
+            let result = resp.u8()?;
+            if result != 0 {
+                return Ok(EpmdResp::Port2Err(result));
+            }
+            let port      = resp.u16()?;
+            let node_type = resp.u8()?;
+            let protocol  = resp.u8()?;
+            let high_ver  = resp.u16()?;
+            let low_ver   = resp.u16()?;
+            let name_len  = resp.u16()? as usize;
+            let name      = resp.name(name_len)?;
+            let extra_len = resp.u16()? as usize;
+            let extra     = resp.bytes(extra_len)?.to_vec();
+            Ok(EpmdResp::Port2Ok(result, port, node_type, protocol, high_ver,
+                                 low_ver, name, extra))
+        }
+        EpmdReq::Names | EpmdReq::Dump => {
+            let port = resp.u32()?;
+            let text = from_utf8(resp.rest())
+                .map_err(|_| DecodeError::InvalidName)?
+                .to_string();
+            match *req {
+                EpmdReq::Names => Ok(EpmdResp::Names(port, text)),
+                _ => Ok(EpmdResp::Dump(port, text)),
+            }
+        }
+        EpmdReq::Kill => {
+            match resp.bytes(2)? {
+                b"OK" => Ok(EpmdResp::KillOk("OK".into())),
+                b"NO" => Ok(EpmdResp::KillErr("NO".into())),
+                op => Err(DecodeError::UnknownOpcode(op[0])),
+            }
+        }
+        EpmdReq::Stop(_) => {
+            match resp.bytes(7)? {
+                b"STOPPED" => Ok(EpmdResp::StopOk("STOPPED".into())),
+                b"NOEXIST" => Ok(EpmdResp::StopErr("NOEXIST".into())),
+                op => Err(DecodeError::UnknownOpcode(op[0])),
+            }
+        }
+    }
+}
+
+/// Reads big-endian fields off the front of a message
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.data;
+        self.data = &[];
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn name(&mut self, len: usize) -> Result<String, DecodeError> {
+        if len > MAX_SYM_LEN {
+            return Err(DecodeError::Oversized(len));
+        }
+        let name = self.bytes(len)?;
+        from_utf8(name)
+            .map(|name| name.to_string())
+            .map_err(|_| DecodeError::InvalidName)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{encode_request, decode_request, request_len};
+    use super::{encode_response, decode_response};
+    use super::{DecodeError, EncodeError, EpmdReq, EpmdResp};
+
+    #[test]
+    fn test_request_round_trip () {
+        let reqs = vec![
+            EpmdReq::Alive2(5555, 77, 0, 6, 5, "foo".into(), vec![1, 2]),
+            EpmdReq::Port2("foo".into()),
+            EpmdReq::Names,
+            EpmdReq::Dump,
+            EpmdReq::Kill,
+            EpmdReq::Stop("foo".into()),
+        ];
+        for req in reqs {
+            assert_eq!(decode_request(&encode_request(&req).unwrap()), Ok(req));
+        }
+        assert_eq!(encode_request(&EpmdReq::Stop("foo".into())),
+                   Ok(vec![0, 4, 115, 102, 111, 111]));
+        assert_eq!(encode_request(&EpmdReq::Names), Ok(vec![0, 1, 110]));
+    }
+
+    #[test]
+    fn test_encode_request_errors () {
+        let long = "a".repeat(1021);
+        assert_eq!(encode_request(&EpmdReq::Port2(long.clone())),
+                   Err(EncodeError::Oversized(1021)));
+        assert_eq!(encode_request(&EpmdReq::Stop(long)),
+                   Err(EncodeError::Oversized(1021)));
+        // The name fits, but not with this much extra data
+        let alive2 = EpmdReq::Alive2(5555, 77, 0, 5, 5, "foo".into(),
+                                     vec![0; 70000]);
+        assert_eq!(encode_request(&alive2),
+                   Err(EncodeError::Oversized(70016)));
+    }
+
+    #[test]
+    fn test_response_round_trip () {
+        let alive2 = EpmdReq::Alive2(5555, 77, 0, 5, 5, "foo".into(), vec![]);
+        let port2 = EpmdReq::Port2("foo".into());
+        let stop = EpmdReq::Stop("foo".into());
+        let resps = vec![
+            (alive2.clone(), EpmdResp::Alive2(0, 3)),
+            (alive2.clone(), EpmdResp::Alive2X(1, 0x01020304)),
+            (port2.clone(), EpmdResp::Port2Err(1)),
+            (port2.clone(), EpmdResp::Port2Ok(0, 5555, 77, 0, 6, 5,
+                                              "foo".into(), vec![1, 2])),
+            (EpmdReq::Names, EpmdResp::Names(4369, "name foo\n".into())),
+            (EpmdReq::Dump, EpmdResp::Dump(4369, "".into())),
+            (EpmdReq::Kill, EpmdResp::KillOk("OK".into())),
+            (EpmdReq::Kill, EpmdResp::KillErr("NO".into())),
+            (stop.clone(), EpmdResp::StopOk("STOPPED".into())),
+            (stop.clone(), EpmdResp::StopErr("NOEXIST".into())),
+        ];
+        for (req, resp) in resps {
+            let data = encode_response(&resp);
+            assert_eq!(decode_response(&req, &data), Ok(resp));
+        }
+        assert_eq!(encode_response(&EpmdResp::Alive2X(0, 0x01020304)),
+                   vec![118, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_request_len () {
+        assert_eq!(request_len(&[]), Ok(None));
+        assert_eq!(request_len(&[0]), Ok(None));
+        assert_eq!(request_len(&[0, 1]), Ok(Some(3)));
+        assert_eq!(request_len(&[0, 1, 110, 0, 1]), Ok(Some(3)));
+        assert_eq!(request_len(&[0, 0]), Err(DecodeError::BadLength(0)));
+        assert_eq!(request_len(&[0xFF, 0xFF]),
+                   Err(DecodeError::BadLength(0xFFFF)));
+    }
+
+    #[test]
+    fn test_decode_request_errors () {
+        assert_eq!(decode_request(&[0]), Err(DecodeError::Truncated));
+        assert_eq!(decode_request(&[0, 4, 115]), Err(DecodeError::Truncated));
+        assert_eq!(decode_request(&[0, 0]), Err(DecodeError::BadLength(0)));
+        assert_eq!(decode_request(&[0, 1, 110, 110]),
+                   Err(DecodeError::BadLength(1)));
+        assert_eq!(decode_request(&[0, 1, 42]),
+                   Err(DecodeError::UnknownOpcode(42)));
+        assert_eq!(decode_request(&[0, 2, 122, 0xFF]),
+                   Err(DecodeError::InvalidName));
+        // ALIVE2 with a name length running past the end of the request
+        let mut alive2 = encode_request(&EpmdReq::Alive2(
+            5555, 77, 0, 5, 5, "foo".into(), vec![])).unwrap();
+        alive2[12] = 9;
+        assert_eq!(decode_request(&alive2), Err(DecodeError::Truncated));
+        // PORT2 with a name longer than any atom
+        let mut port2 = vec![0x04, 0x01, 122];
+        port2.extend_from_slice(&[b'a'; 1024]);
+        assert_eq!(decode_request(&port2), Err(DecodeError::Oversized(1024)));
+    }
+
+    #[test]
+    fn test_decode_response_errors () {
+        let port2 = EpmdReq::Port2("foo".into());
+        assert_eq!(decode_response(&port2, &[119, 0, 21]),
+                   Err(DecodeError::Truncated));
+        assert_eq!(decode_response(&port2, &[121, 0]),
+                   Err(DecodeError::UnknownOpcode(121)));
+        assert_eq!(decode_response(&EpmdReq::Names, &[0, 0]),
+                   Err(DecodeError::Truncated));
+        assert_eq!(decode_response(&EpmdReq::Kill, b"KO"),
+                   Err(DecodeError::UnknownOpcode(b'K')));
+    }
+}
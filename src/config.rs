@@ -0,0 +1,64 @@
+//! How epmd is set up to run; from the command line, the environment or
+//! whoever embeds it.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::check_relaxed;
+use crate::constants::CLOSE_TIMEOUT;
+use crate::socket::{get_address, get_port_number};
+use crate::syslog::SYSLOG_PATH;
+
+#[derive(Debug)]
+pub struct EpmdConfig {
+    // -- program flags --
+    pub debug: u8,         // -d; given more than once for more detail
+    pub silent: bool,
+    pub is_daemon: bool,
+    pub is_systemd: bool,
+    pub brutal_kill: bool, // -relaxed_command_check; allows `kill` & `stop`
+    pub use_ipv6: bool,
+    // -- extra options --
+    pub packet_timeout: Duration,
+    pub delay_accept: usize,
+    pub delay_write: usize,
+    // -- connection properties --
+    pub address: String,
+    pub port: u16,
+    // -- syslog; where a daemon's log messages go --
+    pub syslog_path: PathBuf,
+    pub syslog_facility: libc::c_int,
+    pub syslog_ident: String,
+}
+
+impl EpmdConfig {
+    pub fn new() -> EpmdConfig {
+        EpmdConfig {
+            // -- program flags --
+            debug: 0,
+            silent: false,
+            is_daemon: false,
+            is_systemd: false,
+            brutal_kill: check_relaxed(),
+            use_ipv6: false,
+            // -- extra options --
+            packet_timeout: Duration::new(CLOSE_TIMEOUT, 0),
+            delay_accept: 0,
+            delay_write: 0,
+            // -- connection properties --
+            address: get_address(),
+            port: get_port_number(),
+            // -- syslog --
+            syslog_path: PathBuf::from(SYSLOG_PATH),
+            syslog_facility: libc::LOG_DAEMON,
+            syslog_ident: "epmd".into(),
+            // -- currently unused --
+        }
+    }
+}
+
+impl Default for EpmdConfig {
+    fn default() -> EpmdConfig {
+        EpmdConfig::new()
+    }
+}
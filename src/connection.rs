@@ -1,107 +1,110 @@
 #![allow(dead_code)]
 
-use std::time::{Instant, Duration};
-use std::net::{IpAddr, TcpStream, SocketAddr};
-use std::io::{Read, BufReader};
-use std::io::{Write};
-
-use constants::INBUF_SIZE;
-
-#[derive(Debug)]
-enum Status {
-    Idle,
-    NeedData,
-    NeedResp,
-}
+use std::fmt;
+use std::time::Instant;
+use std::net::{TcpStream, SocketAddr};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 
 #[derive(Debug)]
 pub struct Connection {
     pub can_remove: bool,  // The connection is safe to remove from the list
     pub open: bool,	       // `true` if open
     pub keep: bool,	       // Don't close when sent reply
+    pub closing: bool,     // Close once everything queued has been sent
+    pub paused: bool,      // Not watched until a held back reply is sent
     pub mod_time: Instant, // Last activity on this socket
     pub stream: TcpStream, // TCP connection stream
     peer_addr: SocketAddr, // Remote peer's socket address
-    local_peer: bool,      // This connection is via a local/loopback interface
-    read_buffer: BufReader<TcpStream>,	// The remaining buffer
-    status: Status,
+    local_addr: SocketAddr, // The address the peer connected to
+    outbuf: Vec<u8>,       // Replies not yet taken by the socket
 }
 
 impl Connection {
+    /// Sets up an accepted `stream`; it's made non-blocking, so neither
+    /// reading from nor writing to it can hold up the event loop.
     pub fn new (
         stream: TcpStream,
         peer_addr: SocketAddr,
-        timeout: Duration
-    ) -> Connection {
-        // TODO: Error handling...
-        let _ = stream.set_read_timeout(Some(timeout));
-        let local_addr = stream.local_addr().unwrap();
-        let stream_clone = stream.try_clone().unwrap();
-        Connection {
+    ) -> io::Result<Connection> {
+        stream.set_nonblocking(true)?;
+        let local_addr = stream.local_addr()?;
+        Ok(Connection {
             can_remove: false,
             open: true,
             keep: false,
-            stream: stream,
-            peer_addr: peer_addr,
-            local_peer: is_local_peer(&peer_addr, &local_addr),
-            read_buffer: BufReader::new(stream_clone),
+            closing: false,
+            paused: false,
+            stream,
+            peer_addr,
+            local_addr,
             mod_time: Instant::now(),
-            status: Status::Idle,
-        }
+            outbuf: Vec::new(),
+        })
+    }
+
+    /// Reads whatever data is available on the stream into `buf` and
+    /// returns how much was read; `0` means the peer has closed the
+    /// connection.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_recv = self.stream.read(buf)?;
+        self.mod_time = Instant::now();
+        Ok(bytes_recv)
     }
 
-    /// Reads raw data off of the stream
-    pub fn read(&mut self) -> Vec<u8> {
-        let mut buf = [0; INBUF_SIZE];
-        let bytes_recv = match self.read_buffer.read(&mut buf) {
-            Ok(size) => size,
-            Err(e) => { println!("read() error: {:?}", e);
-                        panic!("");
-                        0 }
-        };
-        println!("DEBUG: Received {} bytes.", bytes_recv);
+    /// The remote peer's address
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
 
-        let mut vec = buf.to_vec();
-        vec.truncate(bytes_recv);
+    /// The address the peer connected to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
 
-        // Error Checking - Correct Length
-        let len = if vec.len() >= 2 {
-            u16::from_be(vec[0] as u16 | (vec[1] as u16) << 8)
-        } else {
-            println!("DEBUG: Received packet too short... :(");
-            0
-        };
-        println!("DEBUG: Expected len: {}\nDEBUG: Received len: {}",
-                 bytes_recv, len + 2);
+    /// Queues raw data to be sent to the remote connection by `flush`
+    pub fn queue(&mut self, response: &[u8]) {
+        self.outbuf.extend_from_slice(response);
+    }
 
-        vec
+    /// `true` while there is queued data the socket hasn't taken yet
+    pub fn has_output(&self) -> bool {
+        !self.outbuf.is_empty()
     }
 
-    /// Sends raw data to the remote connection
-    pub fn write(&mut self, response: Vec<u8>) {
-        let _ = self.stream.write_all(&response);
-        let _ = self.stream.flush();
+    /// Sends as much of the queued data as the socket will take without
+    /// blocking; `true` once all of it has been sent.
+    pub fn flush(&mut self) -> io::Result<bool> {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(&self.outbuf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outbuf.drain(..n);
+                    self.mod_time = Instant::now();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
     }
 
     /// Signals that the Connection should be dropped
     pub fn close(&mut self) {
         self.keep = false;
+        self.closing = false;
         self.open = false;
-        //let _ = conn.stream.shutdown(Shutdown::Both);
     }
 
 }
 
-/// Function to check to see if the connection comes from a local peer.
-/// This function checks the loopback interface and other local addresses.
-fn is_local_peer(sock_peer: &SocketAddr, sock_local: &SocketAddr) -> bool {
-    // NOTE: IpAddr.is_loopback() is stable since Rust 1.12; Earlier versions
-    // need to get the IpAddrV4/6.is_loopback() respectively.
-    //let is_loopback  = sock_peer.ip().is_loopback();
-    let is_loopback = match sock_peer.ip() {
-        IpAddr::V4(ref a) => a.is_loopback(),
-        IpAddr::V6(ref a) => a.is_loopback(),
-    };
-    let is_same_addr = sock_peer.ip() == sock_local.ip();
-    (is_loopback || is_same_addr)
+/// Identifies the connection in log messages
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (fd {})", self.peer_addr, self.stream.as_raw_fd())
+    }
 }
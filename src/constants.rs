@@ -52,27 +52,31 @@ const EPMD_NODE_TYPE: u16  = 110;
 const DIST_LOW:  u16 = 5;
 const DIST_HIGH: u16 = 5;
 
+// Distribution version 6 (OTP-23) uses 32-bit creations; nodes which can
+// speak it get an `ALIVE2_X_RESP` rather than an `ALIVE2_RESP`.
+pub const DIST_VSN_32BIT_CREATION: u16 = 6;
+
 // from /erts/epmd/src/epmd.h
 
 /* Definitions of message codes */
 
 /* Registration and queries */
-const ALIVE2_REQ:  u8 = 120; // 'x'
-const PORT2_REQ:   u8 = 122; // 'z'
-const NAMES_REQ:   u8 = 110; // 'n'
+pub const ALIVE2_REQ:  u8 = 120; // 'x'
+pub const PORT2_REQ:   u8 = 122; // 'z'
+pub const NAMES_REQ:   u8 = 110; // 'n'
 
 pub const ALIVE2_RESP: u8 = 121; // 'y'
+pub const ALIVE2_X_RESP: u8 = 118; // 'v'
 pub const PORT2_RESP:  u8 = 119; // 'w'
 
 /* Interactive client command codes */
-const DUMP_REQ: u8 = 100; // 'd'
-const KILL_REQ: u8 = 107; // 'k'
-const STOP_REQ: u8 = 115; // 's'
+pub const DUMP_REQ: u8 = 100; // 'd'
+pub const KILL_REQ: u8 = 107; // 'k'
+pub const STOP_REQ: u8 = 115; // 's'
 
 // from /erts/epmd/src/epmd_int.h
 // `-> (at least selection from here...)
 
-// If no activity we let select() return every IDLE_TIMEOUT second
 // A file descriptor that has been idle for CLOSE_TIMEOUT seconds and
 // isn't an ALIVE socket has probably hanged and should be closed
 pub const IDLE_TIMEOUT:  i64 = 5;
@@ -82,29 +86,20 @@ pub const CLOSE_TIMEOUT: u64 = 60;
 // node register the name we want to increment the "creation",
 // a constant 1..3. But we put an limit to this saving to keep
 // the lookup fast and not to leak memory.
-const MAX_UNREG_COUNT: usize       = 1000;
-const DEBUG_MAX_UNREG_COUNT: usize = 5;
+pub const MAX_UNREG_COUNT: usize       = 1000;
+pub const DEBUG_MAX_UNREG_COUNT: usize = 5;
 
 // Maximum length of a node name == atom name is 255 characters;
 // encoded in UTF-8 this gives a max of (255*4) or 1020 bytes.
-const MAX_SYM_LEN: usize = 1020;
+pub const MAX_SYM_LEN: usize = 1020;
 // NOTE: Since this is just the name as an atom, which is utf8, then
 // we can just set this to the max atom length in Erlang, which is:
 const MAX_ATOM_LEN: usize = 255;
 
 pub const MAX_LISTEN_SOCKETS: usize = 16;
 
-/* TODO/NOTE: Decide on the maximum number of socket connections
-This is apparently a strangely hard to define parameter between different
-platforms... This is set once as less than `libc::FD_SETSIZE` if it exists,
-or `MAX_FILE` which is defined in the constants...
-so, MAX_FILE is defined here to be 2048, which the default for FD_SETSIZE
-can, theoretically, be user defined but is set in the Unix's to 1024, which
-is probably the limit that most people will use. We can go looking for a
-value with ENV_VAR && with libc::FD_SETSIZE, but the default of 1024 should
-be fine... I hope...
- */
-//const MAX_FILE: usize = 2048;
+// The number of connections we allow when the system doesn't limit how many
+// files we may open; otherwise that limit, `RLIMIT_NOFILE`, is used.
 pub const MAX_FILE_DESCRIPTORS: usize = 1024;
 
 // Largest request: ALIVE2_REQ
@@ -112,8 +107,8 @@ pub const MAX_FILE_DESCRIPTORS: usize = 1024;
 // Largest response: PORT2_RESP
 //     2 + 14 + 2*MAXSYMLEN
 // That is, 3*MAXSYMLEN should be large enough
-pub const INBUF_SIZE:  usize = (3 * MAX_SYM_LEN);
-const OUTBUF_SIZE: usize = (3 * MAX_SYM_LEN);
+pub const INBUF_SIZE:  usize = 3 * MAX_SYM_LEN;
+const OUTBUF_SIZE: usize = 3 * MAX_SYM_LEN;
 
 // sets the sockets to only use ipv6
 // TODO: Have this option as a feature / configuration option
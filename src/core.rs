@@ -0,0 +1,684 @@
+//! The port mapper's protocol handling, without any I/O.
+//!
+//! `EpmdCore` is told about connections coming and going and the bytes that
+//! arrive on them, and answers with the `Action`s to take; it never reads
+//! from or writes to a connection itself. What it does besides is log,
+//! through the `Logger` in `Epmd`, and pick creations for new nodes, through
+//! `Epmd::new_creation`; both can be swapped out, e.g. in tests. The event
+//! loop in `epmd.rs` and the tokio server are both thin adapters around it,
+//! and it can be driven from any other event loop, or from a test, the same
+//! way.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use libc::c_int;
+
+use crate::codec::{
+    decode_request, encode_response, request_len, EpmdReq, EpmdResp,
+};
+use crate::config::EpmdConfig;
+use crate::constants::MAX_FILE_DESCRIPTORS;
+use crate::erl_node::{random_creation, ErlNode};
+use crate::log::Logger;
+use crate::registry::Registry;
+
+#[derive(Debug)]
+pub struct Epmd {
+    // -- program data --
+    pub active_conn: usize,
+    pub max_conn: usize,
+    pub nodes: Registry,
+    pub close_fds: Vec<libc::c_int>, // Connections which should be closed
+    pub shutdown: bool,              // Set once we've been asked to exit
+    pub log: Logger,
+    // Picks the creation of a node registering a name for the first time,
+    // given whether it takes 32-bit creations; random unless replaced.
+    pub new_creation: fn(bool) -> u32,
+}
+
+impl Epmd {
+    pub fn new () -> Epmd {
+        Epmd {
+            active_conn: 0,
+            max_conn: MAX_FILE_DESCRIPTORS,
+            nodes: Registry::new(),
+            close_fds: Vec::new(),
+            shutdown: false,
+            log: Logger::default(),
+            new_creation: random_creation,
+        }
+    }
+}
+
+impl Default for Epmd {
+    fn default() -> Epmd {
+        Epmd::new()
+    }
+}
+
+/// The details of a connection that requests are handled against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub fd: c_int,     // Socket the request arrived on
+    pub local: bool,   // The request came via a local/loopback interface
+}
+
+/// Identifies a connection; `epmd::serve` uses the socket's fd, the async
+/// server numbers them as they're accepted. Nodes are registered against
+/// the connection they registered on.
+pub type ConnId = c_int;
+
+/// What the event loop should do after handing data to `EpmdCore`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Send these bytes on the connection
+    Write(ConnId, Vec<u8>),
+    /// Close the connection, once anything written before has been sent;
+    /// `on_close` should be called when it is.
+    Close(ConnId),
+    /// A node has registered on the connection; keep it open for as long as
+    /// the peer does, without a timeout.
+    KeepAlive(ConnId),
+}
+
+impl Action {
+    /// The connection this is to be done on
+    pub fn conn(&self) -> ConnId {
+        match *self {
+            Action::Write(id, _) | Action::Close(id) | Action::KeepAlive(id) => id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Conn {
+    name: ConnName, // Who this is, for log messages
+    local: bool,    // Connected via a local/loopback interface
+    keep: bool,     // A node is registered on this connection
+    closing: bool,  // We've said to close it; anything more is ignored
+    inbuf: Vec<u8>, // Received data not yet handled as a request
+}
+
+/// Identifies a connection in log messages, the same way the servers do
+#[derive(Debug, Clone, Copy)]
+struct ConnName {
+    peer_addr: SocketAddr,
+    id: ConnId,
+}
+
+impl fmt::Display for ConnName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (fd {})", self.peer_addr, self.id)
+    }
+}
+
+#[derive(Debug)]
+pub struct EpmdCore {
+    pub epmd: Epmd,
+    pub config: EpmdConfig,
+    conns: HashMap<ConnId, Conn>,
+}
+
+impl EpmdCore {
+    pub fn new(epmd: Epmd, config: EpmdConfig) -> EpmdCore {
+        EpmdCore { epmd, config, conns: HashMap::new() }
+    }
+
+    /// A new connection from `peer_addr`, made to our `local_addr`; only
+    /// peers which connected through a local interface can use `kill`,
+    /// `stop` or `dump`.
+    pub fn on_connect(
+        &mut self,
+        id: ConnId,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) {
+        let name = ConnName { peer_addr, id };
+        let local = is_local_peer(&peer_addr, &local_addr);
+        self.conns.insert(id, Conn { name, local, keep: false,
+                                     closing: false, inbuf: Vec::new() });
+    }
+
+    /// Data has arrived on the connection `id`; returns what to do about it,
+    /// in order. A request can arrive split over any number of calls, and
+    /// several can arrive in one. Once we've said to close a connection,
+    /// whatever else arrives on it is ignored.
+    pub fn on_data(&mut self, id: ConnId, data: &[u8]) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let conn = match self.conns.get_mut(&id) {
+            Some(conn) if conn.closing => return actions,
+            Some(conn) => conn,
+            None => {
+                actions.push(Action::Close(id));
+                return actions;
+            }
+        };
+        conn.inbuf.extend_from_slice(data);
+        let peer = Peer { fd: id, local: conn.local };
+        let name = conn.name;
+
+        loop {
+            let conn = self.conns.get_mut(&id).expect("connection is open");
+            let mesg = match request_len(&conn.inbuf) {
+                Ok(Some(len)) if len <= conn.inbuf.len() => {
+                    conn.inbuf.drain(..len).collect::<Vec<_>>()
+                }
+                Ok(_) => break,
+                Err(err) => {
+                    // A length which can never be satisfied
+                    info!(self.epmd.log, "{}: bad request: {}", name, err);
+                    actions.push(Action::Close(id));
+                    break;
+                }
+            };
+            let response = match decode_request(&mesg) {
+                Ok(request) => {
+                    debug!(self.epmd.log, "{}: got request {:?}",
+                           name, request);
+                    process_request(&mut self.epmd, &self.config, peer, request)
+                }
+                Err(err) => {
+                    info!(self.epmd.log, "{}: bad request: {}", name, err);
+                    EpmdResp::None
+                }
+            };
+            // The connections of nodes which were stopped
+            for fd in self.epmd.close_fds.drain(..) {
+                actions.push(Action::Close(fd));
+            }
+
+            // Only a successful ALIVE2 registration outlives its reply;
+            // every other reply is terminated by closing.
+            let registered = matches!(response,
+                EpmdResp::Alive2(0, _) | EpmdResp::Alive2X(0, _));
+            if response != EpmdResp::None {
+                debug!(self.epmd.log, "{}: sending response {:?}",
+                       name, response);
+                actions.push(Action::Write(id, encode_response(&response)));
+            }
+            let conn = self.conns.get_mut(&id).expect("connection is open");
+            if registered {
+                conn.keep = true;
+                actions.push(Action::KeepAlive(id));
+            } else if !conn.keep {
+                actions.push(Action::Close(id));
+                break;
+            }
+        }
+        for action in &actions {
+            if let Action::Close(fd) = *action {
+                if let Some(conn) = self.conns.get_mut(&fd) {
+                    conn.closing = true;
+                }
+            }
+        }
+        actions
+    }
+
+    /// The connection `id` has been closed; by either side, or because it
+    /// timed out. A node registered on it is unregistered.
+    pub fn on_close(&mut self, id: ConnId) {
+        self.conns.remove(&id);
+        unregister_node(&mut self.epmd, id);
+    }
+
+    /// `true` if a node is registered on the connection `id`
+    pub fn is_kept(&self, id: ConnId) -> bool {
+        self.conns.get(&id).is_some_and(|conn| conn.keep)
+    }
+
+    /// `true` once we've been killed
+    pub fn is_shutdown(&self) -> bool {
+        self.epmd.shutdown
+    }
+}
+
+fn process_request(
+    epmd: &mut Epmd,
+    config: &EpmdConfig,
+    peer: Peer,
+    req: EpmdReq
+) -> EpmdResp {
+    match req {
+        EpmdReq::Alive2(port, n_type, proto, h_ver, l_ver, name, extra) => {
+            let mut node = ErlNode::new(peer.fd, port, n_type, proto, h_ver,
+                                        l_ver, name.clone(), extra);
+            node.creation = (epmd.new_creation)(node.wants_x_resp());
+            let wants_x_resp = node.wants_x_resp();
+            let (result, creation) = match epmd.nodes.register(node) {
+                Ok(()) => {
+                    let node = epmd.nodes.get(&name).expect("just registered");
+                    info!(epmd.log, "registered {} at port {} (fd {}), \
+                                     creation {}",
+                          node.name, node.port, node.fd, node.creation);
+                    (0 /* OK */, node.creation)
+                }
+                Err(node) if epmd.nodes.get_by_fd(node.fd).is_some() => {
+                    // A connection carries exactly one registration
+                    info!(epmd.log, "fd {}: rejected registration of {}; a \
+                                     node is already registered on it",
+                          node.fd, node.name);
+                    (1 /* Error */, 99)
+                }
+                Err(node) => {
+                    // The name is still held by a live connection
+                    info!(epmd.log, "fd {}: rejected registration; {} is \
+                                     already registered", node.fd, node.name);
+                    (1 /* Error */, 99)
+                }
+            };
+            if wants_x_resp {
+                EpmdResp::Alive2X(result, creation)
+            } else {
+                EpmdResp::Alive2(result, creation as u16)
+            }
+        }
+        EpmdReq::Port2(name) => {
+            match epmd.nodes.get(&name) {
+                Some(node) => {
+                    EpmdResp::Port2Ok(0 /* OK */, node.port, node.node_type,
+                                      node.protocol, node.high_version,
+                                      node.low_version, node.name.clone(),
+                                      node.extra.clone())
+                }
+                None => EpmdResp::Port2Err(1 /* Unknown name */)
+            }
+        },
+        EpmdReq::Names => {
+            let mut names = String::new();
+            for node in epmd.nodes.iter() {
+                names.push_str(
+                    &format!("name {} at port {}\n", node.name, node.port));
+            }
+            EpmdResp::Names(config.port as u32, names)
+        },
+        EpmdReq::Dump => {
+            if !peer.local {
+                // Only local peers get to see the node database
+                return EpmdResp::None;
+            }
+            let mut dump = String::new();
+            for node in epmd.nodes.iter() {
+                dump.push_str(
+                    &format!("active name     <{}> at port {}, fd = {}\n",
+                             node.name, node.port, node.fd));
+            }
+            for node in epmd.nodes.unreg() {
+                dump.push_str(
+                    &format!("old/unused name <{}>, port = {}, fd = {}, \
+                              creation = {}\n",
+                             node.name, node.port, node.fd, node.creation));
+            }
+            EpmdResp::Dump(config.port as u32, dump)
+        },
+        EpmdReq::Kill => {
+            if !peer.local {
+                info!(epmd.log, "fd {}: rejected kill from a remote peer",
+                      peer.fd);
+                return EpmdResp::None;
+            }
+            if !config.brutal_kill && !epmd.nodes.is_empty() {
+                info!(epmd.log, "fd {}: rejected kill; living nodes in \
+                                 database", peer.fd);
+                return EpmdResp::KillErr("NO".into());
+            }
+            epmd.shutdown = true;
+            EpmdResp::KillOk("OK".into())
+        },
+        EpmdReq::Stop(name) => {
+            if !peer.local {
+                info!(epmd.log, "fd {}: rejected stop from a remote peer",
+                      peer.fd);
+                return EpmdResp::None;
+            }
+            if !config.brutal_kill {
+                info!(epmd.log, "fd {}: rejected stop; no \
+                                 -relaxed_command_check", peer.fd);
+                return EpmdResp::None;
+            }
+            match epmd.nodes.unregister(&name) {
+                Some(node) => {
+                    info!(epmd.log, "stopped {} at port {} (fd {})",
+                          node.name, node.port, node.fd);
+                    epmd.close_fds.push(node.fd);
+                    EpmdResp::StopOk("STOPPED".into())
+                }
+                None => EpmdResp::StopErr("NOEXIST".into())
+            }
+        },
+    }
+}
+
+/// Removes the node registered on the connection `fd`, if there is one
+fn unregister_node(epmd: &mut Epmd, fd: c_int) {
+    if let Some(node) = epmd.nodes.unregister_fd(fd) {
+        info!(epmd.log, "unregistered {} at port {} (fd {})",
+              node.name, node.port, node.fd);
+    }
+}
+
+/// Function to check to see if the connection comes from a local peer.
+/// This function checks the loopback interface and other local addresses.
+fn is_local_peer(sock_peer: &SocketAddr, sock_local: &SocketAddr) -> bool {
+    // NOTE: IpAddr.is_loopback() is stable since Rust 1.12; Earlier versions
+    // need to get the IpAddrV4/6.is_loopback() respectively.
+    //let is_loopback  = sock_peer.ip().is_loopback();
+    let is_loopback = match sock_peer.ip() {
+        IpAddr::V4(ref a) => a.is_loopback(),
+        IpAddr::V6(ref a) => a.is_loopback(),
+    };
+    let is_same_addr = sock_peer.ip() == sock_local.ip();
+    is_loopback || is_same_addr
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::codec::{encode_request, encode_response, EpmdReq, EpmdResp};
+    use crate::config::EpmdConfig;
+
+    use super::{process_request, unregister_node};
+    use super::{Action, ConnId, Epmd, EpmdCore, Peer};
+
+    const LOCAL: Peer = Peer { fd: 7, local: true };
+    const REMOTE: Peer = Peer { fd: 8, local: false };
+
+    fn core() -> EpmdCore {
+        let config = EpmdConfig { port: 4369, brutal_kill: true,
+                                  ..EpmdConfig::new() };
+        EpmdCore::new(Epmd::new(), config)
+    }
+
+    /// Connects `id`, from a loopback address if it's `local`
+    fn connect(core: &mut EpmdCore, id: ConnId, local: bool) {
+        let peer = if local { "127.0.0.1:40000" } else { "10.0.0.2:40000" };
+        core.on_connect(id, peer.parse().unwrap(),
+                        "10.0.0.1:4369".parse().unwrap());
+    }
+
+    fn alive2(name: &str) -> Vec<u8> {
+        encode_request(
+            &EpmdReq::Alive2(5555, 77, 0, 5, 5, name.to_string(), vec![]))
+            .unwrap()
+    }
+
+    fn alive2_req(name: &str, port: u16) -> EpmdReq {
+        EpmdReq::Alive2(port, 77, 0, 5, 5, name.to_string(), vec![])
+    }
+
+    fn names_reply(names: &str) -> Vec<u8> {
+        encode_response(&EpmdResp::Names(4369, names.into()))
+    }
+
+    #[test]
+    fn test_request_split_over_calls () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        let mesg = encode_request(&EpmdReq::Names).unwrap();
+        let (last, head) = mesg.split_last().unwrap();
+        for byte in head {
+            assert_eq!(core.on_data(7, &[*byte]), vec![]);
+        }
+        assert_eq!(core.on_data(7, &[*last]),
+                   vec![Action::Write(7, names_reply("")), Action::Close(7)]);
+    }
+
+    #[test]
+    fn test_pipelined_requests_after_registration () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        let mut data = alive2("foo");
+        data.extend(encode_request(&EpmdReq::Names).unwrap());
+        let actions = core.on_data(7, &data);
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(actions[0], Action::Write(7, _)));
+        assert_eq!(actions[1], Action::KeepAlive(7));
+        // Still registered, so the connection stays open after the reply
+        assert_eq!(actions[2],
+                   Action::Write(7, names_reply("name foo at port 5555\n")));
+    }
+
+    #[test]
+    fn test_second_alive2_on_connection_is_rejected () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        core.on_data(7, &alive2("foo"));
+        let actions = core.on_data(7, &alive2("bar"));
+        assert_eq!(actions, vec![
+            Action::Write(7, encode_response(&EpmdResp::Alive2(1, 99))),
+        ]);
+        assert!(core.epmd.nodes.get("bar").is_none());
+        core.on_close(7);
+        assert!(core.epmd.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_request_is_rejected () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        assert_eq!(core.on_data(7, &[0xFF, 0xFF, 110]),
+                   vec![Action::Close(7)]);
+    }
+
+    #[test]
+    fn test_close_unregisters_node () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        core.on_data(7, &alive2("foo"));
+        assert!(core.is_kept(7));
+        core.on_close(7);
+        assert!(!core.is_kept(7));
+        assert!(core.epmd.nodes.get("foo").is_none());
+        assert_eq!(core.on_data(7, b"\0\x01n"), vec![Action::Close(7)]);
+    }
+
+    #[test]
+    fn test_stop_closes_nodes_connection () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        core.on_data(7, &alive2("foo"));
+        connect(&mut core, 8, true);
+        let stop = encode_request(&EpmdReq::Stop("foo".into())).unwrap();
+        let stopped = encode_response(&EpmdResp::StopOk("STOPPED".into()));
+        assert_eq!(core.on_data(8, &stop), vec![
+            Action::Close(7), Action::Write(8, stopped), Action::Close(8),
+        ]);
+    }
+
+    #[test]
+    fn test_kill () {
+        let mut core = core();
+        connect(&mut core, 7, false);
+        // Only for local peers
+        let kill = encode_request(&EpmdReq::Kill).unwrap();
+        assert_eq!(core.on_data(7, &kill), vec![Action::Close(7)]);
+        assert!(!core.is_shutdown());
+        connect(&mut core, 8, true);
+        core.on_data(8, &kill);
+        assert!(core.is_shutdown());
+    }
+
+    #[test]
+    fn test_requests_after_close_are_ignored () {
+        let mut core = core();
+        connect(&mut core, 7, true);
+        let mut data = encode_request(&EpmdReq::Names).unwrap();
+        assert_eq!(core.on_data(7, &data),
+                   vec![Action::Write(7, names_reply("")), Action::Close(7)]);
+        // Still open until the reply has gone out, but done with requests
+        let kill = encode_request(&EpmdReq::Kill).unwrap();
+        assert_eq!(core.on_data(7, &kill), vec![]);
+        assert!(!core.is_shutdown());
+        // Same for a request in the same read as the one closing it
+        connect(&mut core, 8, true);
+        data.extend(&kill);
+        assert_eq!(core.on_data(8, &data),
+                   vec![Action::Write(8, names_reply("")), Action::Close(8)]);
+        assert!(!core.is_shutdown());
+    }
+
+    #[test]
+    fn test_port2_registered_node () {
+        let mut epmd = Epmd::new();
+        let config = EpmdConfig::new();
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 0x4321));
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Port2("foo".into()));
+        assert_eq!(resp, EpmdResp::Port2Ok(0, 0x4321, 77, 0, 5, 5,
+                                           "foo".into(), vec![]));
+    }
+
+    #[test]
+    fn test_port2_unknown_node () {
+        let mut epmd = Epmd::new();
+        let config = EpmdConfig::new();
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 0x4321));
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Port2("bar".into()));
+        assert_eq!(resp, EpmdResp::Port2Err(1));
+    }
+
+    #[test]
+    fn test_names_lists_registered_nodes () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.port = 4369;
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 0x4321));
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Names);
+        assert_eq!(resp, EpmdResp::Names(4369,
+                                         "name foo at port 17185\n".into()));
+    }
+
+    #[test]
+    fn test_names_empty () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.port = 4369;
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Names);
+        assert_eq!(resp, EpmdResp::Names(4369, "".into()));
+    }
+
+    #[test]
+    fn test_dump_active_and_old_nodes () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.port = 4369;
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let bar = Peer { fd: 9, local: true };
+        process_request(&mut epmd, &config, bar, alive2_req("bar", 6666));
+        unregister_node(&mut epmd, bar.fd);
+        let creation = epmd.nodes.unreg().next().unwrap().creation;
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Dump);
+        assert_eq!(resp, EpmdResp::Dump(4369, format!("\
+            active name     <foo> at port 5555, fd = 7\n\
+            old/unused name <bar>, port = 6666, fd = 9, creation = {}\n",
+            creation)));
+    }
+
+    #[test]
+    fn test_dump_ignores_remote_peers () {
+        let mut epmd = Epmd::new();
+        let config = EpmdConfig::new();
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let resp = process_request(&mut epmd, &config, REMOTE, EpmdReq::Dump);
+        assert_eq!(resp, EpmdResp::None);
+    }
+
+    #[test]
+    fn test_unregister_node_on_connection_close () {
+        let mut epmd = Epmd::new();
+        let config = EpmdConfig::new();
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        unregister_node(&mut epmd, LOCAL.fd);
+        let resp = process_request(&mut epmd, &config, LOCAL,
+                                   EpmdReq::Port2("foo".into()));
+        assert_eq!(resp, EpmdResp::Port2Err(1));
+        assert_eq!(epmd.nodes.unreg().count(), 1);
+        assert_eq!(epmd.nodes.unreg().next().unwrap().name, "foo");
+    }
+
+    #[test]
+    fn test_alive2_rejects_duplicate_name () {
+        let mut epmd = Epmd::new();
+        let config = EpmdConfig::new();
+        let other = Peer { fd: 9, local: true };
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let resp = process_request(&mut epmd, &config, other,
+                                   alive2_req("foo", 6666));
+        assert_eq!(resp, EpmdResp::Alive2(1, 99));
+        let resp = process_request(&mut epmd, &config, LOCAL,
+                                   EpmdReq::Port2("foo".into()));
+        assert_eq!(resp, EpmdResp::Port2Ok(0, 5555, 77, 0, 5, 5,
+                                           "foo".into(), vec![]));
+    }
+
+    #[test]
+    fn test_alive2_x_resp_for_otp23_nodes () {
+        let mut epmd = Epmd::new();
+        epmd.new_creation = |wide| if wide { 0x01020304 } else { 2 };
+        let config = EpmdConfig::new();
+        let req = EpmdReq::Alive2(5555, 77, 0, 6, 5, "foo".into(), vec![]);
+        let resp = process_request(&mut epmd, &config, LOCAL, req);
+        assert_eq!(resp, EpmdResp::Alive2X(0, 0x01020304));
+        let resp = process_request(&mut epmd, &config, REMOTE,
+                                   alive2_req("bar", 6666));
+        assert_eq!(resp, EpmdResp::Alive2(0, 2));
+    }
+
+    #[test]
+    fn test_stop_unregisters_node () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.brutal_kill = true;
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let other = Peer { fd: 9, local: true };
+        let resp = process_request(&mut epmd, &config, other,
+                                   EpmdReq::Stop("foo".into()));
+        assert_eq!(resp, EpmdResp::StopOk("STOPPED".into()));
+        assert_eq!(epmd.close_fds, vec![LOCAL.fd]);
+        assert!(epmd.nodes.get("foo").is_none());
+        assert_eq!(epmd.nodes.unreg().next().unwrap().name, "foo");
+        let resp = process_request(&mut epmd, &config, other,
+                                   EpmdReq::Stop("foo".into()));
+        assert_eq!(resp, EpmdResp::StopErr("NOEXIST".into()));
+    }
+
+    #[test]
+    fn test_stop_needs_relaxed_command_check () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.brutal_kill = false;
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let resp = process_request(&mut epmd, &config, LOCAL,
+                                   EpmdReq::Stop("foo".into()));
+        assert_eq!(resp, EpmdResp::None);
+        assert!(epmd.nodes.get("foo").is_some());
+    }
+
+    #[test]
+    fn test_kill_refused_with_living_nodes () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.brutal_kill = false;
+        process_request(&mut epmd, &config, LOCAL, alive2_req("foo", 5555));
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Kill);
+        assert_eq!(resp, EpmdResp::KillErr("NO".into()));
+        assert!(!epmd.shutdown);
+        config.brutal_kill = true;
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Kill);
+        assert_eq!(resp, EpmdResp::KillOk("OK".into()));
+        assert!(epmd.shutdown);
+    }
+
+    #[test]
+    fn test_kill_empty_registry () {
+        let mut epmd = Epmd::new();
+        let mut config = EpmdConfig::new();
+        config.brutal_kill = false;
+        let resp = process_request(&mut epmd, &config, REMOTE, EpmdReq::Kill);
+        assert_eq!(resp, EpmdResp::None);
+        let resp = process_request(&mut epmd, &config, LOCAL, EpmdReq::Kill);
+        assert_eq!(resp, EpmdResp::KillOk("OK".into()));
+        assert!(epmd.shutdown);
+    }
+}
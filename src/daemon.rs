@@ -1,7 +1,10 @@
-use libc;
+use std::io;
 use std::mem;
 
-use epmd::{self, Epmd, EpmdConfig};
+use crate::config::EpmdConfig;
+use crate::core::Epmd;
+use crate::epmd;
+use crate::error::EpmdError;
 
 /// Starts a new epmd daemon process
 ///
@@ -19,7 +22,7 @@ use epmd::{self, Epmd, EpmdConfig};
 ///  * Create a normal process (Parent process).
 ///  * Create a child process from within the above parent process.
 ///    The process hierarchy at this stage looks like:
-///        TERMINAL -> PARENT PROCESS -> CHILD PROCESS
+///    TERMINAL -> PARENT PROCESS -> CHILD PROCESS
 ///  * Terminate the the parent process.
 ///  * The child process is now orphaned and is taken over by the init process.
 ///  * Call setsid() fn to run the process in new session and have a new group.
@@ -33,8 +36,10 @@ use epmd::{self, Epmd, EpmdConfig};
 /// [1] : http://www.thegeekstuff.com/2012/02/c-daemon-process
 /// [2] : http://www.netzmafia.de/skripten/unix/linux-daemon-howto.html
 /// [3] : https://www.gnu.org/software/libc/manual/html_node/Termination-Internals.html#Termination-Internals
-
-pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
+///
+/// The processes left behind by the forks return `Ok` straight away so they
+/// can exit; only the daemon itself goes on to serve requests.
+pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) -> Result<(), EpmdError> {
 
     // create the parent process
     // NOTE: For the `fork()` call:
@@ -43,9 +48,9 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
     //  * In case of error the return value == -1
     let   child_pid = unsafe { libc::fork() };
     match child_pid {
-        -1 => panic!("Erlang mapper daemon can't fork"),
-         0 => return, // Parent should exit
-         _ => () // continue
+        -1 => return Err(daemon_error("fork()")),
+         0 => (),              // continue
+         _ => return Ok(()),   // Parent should exit
     }
 
     // Become the session leader
@@ -53,7 +58,7 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
     //  * Returns new process group ID if successful
     //  * Returns `(pid_t) -1`, i.e. -1:i32, and sets `ERRNO`
     let sid = unsafe { libc::setsid() };
-    if  sid < 0 { panic!("epmd: Can't `setsid()`"); }
+    if  sid < 0 { return Err(daemon_error("setsid()")); }
 
     // NOTE: This next part comes from the process termination process.
     // FROM: [3]
@@ -66,18 +71,18 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
     // We don't want to actually be the session leader so fork again
     let   child_pid = unsafe { libc::fork() };
     match child_pid {
-        -1 => panic!("Erlang mapper daemon can't complete second fork"),
-        0  => return, // Parent should exit
-        _  => ()      // continue
+        -1 => return Err(daemon_error("second fork()")),
+        0  => (),              // continue
+        _  => return Ok(()),   // Parent should exit
     }
 
     // Move our current working directory to root;
     // to make sure we're not on a mounted file system.
     let chdir = unsafe {
-        let path: *const i8 = mem::transmute("/".as_ptr());
+        let path = b"/\0".as_ptr() as *const libc::c_char;
         libc::chdir(path)
     };
-    if chdir < 0 { panic!("epmd: `chdir()` failed"); }
+    if chdir < 0 { return Err(daemon_error("chdir()")); }
 
     // Set the `umask` to `0` which means that this process's file permissions
     // are determined by the system; This need to be changed because the
@@ -87,7 +92,7 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
     // Close all open file handles;
     // this includes the default ones for `stdin` etc..
     unsafe {
-        let mut limit = mem::uninitialized();
+        let mut limit: libc::rlimit = mem::zeroed();
         let _ = libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
         for fd in 0..limit.rlim_max {
             // TODO: CHECK SAFETY of type cast from u64 -> i32 here
@@ -105,7 +110,7 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
     //   This is because the POSIX standard file descriptors are defined
     //   as 0, 1, & 2 in for std-in, -out, and -err respectively.
     unsafe {
-        let dev_null: *const i8  = mem::transmute("/dev/null".as_ptr());
+        let dev_null = b"/dev/null\0".as_ptr() as *const libc::c_char;
         libc::open(dev_null, libc::O_RDONLY);
         libc::open(dev_null, libc::O_WRONLY);
         libc::open(dev_null, libc::O_WRONLY);
@@ -118,7 +123,12 @@ pub fn run_daemon_unix (epmd: Epmd, config: EpmdConfig) {
         *errno = 0;
     }
 
-    epmd::run(epmd, config);
+    epmd::run(epmd, config)
+}
+
+/// The error for a failed step in becoming a daemon; from `errno`
+fn daemon_error(what: &'static str) -> EpmdError {
+    EpmdError::Daemon(what, io::Error::last_os_error())
 }
 
 // TODO: Write the windows version of this function
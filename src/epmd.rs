@@ -1,446 +1,533 @@
-/// Citations:
-/// [1]: https://msdn.microsoft.com/en-us/library/windows/desktop/ms741563.aspx
-/// [2]: https://lists.fedoraproject.org/pipermail/devel/2010-July/139135.html
+// Citations:
+// [1]: https://msdn.microsoft.com/en-us/library/windows/desktop/ms741563.aspx
+// [2]: https://lists.fedoraproject.org/pipermail/devel/2010-July/139135.html
 
-use std::io::{Result, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
 use std::time::{Duration, Instant};
-use std::collections::HashSet;
-use std::str::from_utf8;
-use std::net::Shutdown;
-#[cfg(any(unix))]
+use std::cmp;
+use std::net::{Shutdown, TcpListener};
+#[cfg(unix)]
 use std::os::unix::io::AsRawFd;
 
-use libc;
-
-use connection::Connection;
-use constants::{
-    MAX_LISTEN_SOCKETS, CLOSE_TIMEOUT, MAX_FILE_DESCRIPTORS,
-    ALIVE2_RESP, PORT2_RESP
-};
-use erl_node::ErlNode;
-use libc_utils;
-use socket::{
-    parse_socket_addrs, create_listen_sockets, get_address, get_port_number
+use crate::error::EpmdError;
+use crate::config::EpmdConfig;
+use crate::connection::Connection;
+use crate::core::{Action, Epmd, EpmdCore};
+use crate::constants::{
+    MAX_LISTEN_SOCKETS, DEBUG_MAX_UNREG_COUNT, INBUF_SIZE,
 };
+use crate::systemd;
+use crate::libc_utils::{self, SigTerm};
+use crate::log::{Level, Logger};
+use crate::poll::Poll;
+use crate::server::Control;
+use crate::socket::{parse_socket_addrs, create_listen_sockets};
 
-#[derive(Debug)]
-pub enum EpmdReq {
-    None,
-    // port, type, protocol, high_ver, low_ver, name, extra
-    Alive2(u16, u8, u8, u16, u16, String, Vec<u8>),
-    Port2(String), // Name
-    Names,
-    Dump,
-    Kill,
-    Stop(String) // Name
-}
-
-#[derive(Debug, Eq, PartialEq)]
-#[allow(dead_code)]
-pub enum EpmdResp {
-    None,
-    Alive2(u8, u16), // Result, Creation
-    Port2Err(u8),    // just result is given if error.
-    // result, port, type, protocol, high_ver, low_ver, name, extra
-    Port2Ok(u8, u16, u8, u8, u16, u16, String, Vec<u8>),
-    Names(u32, String),
-    Dump(u32, String),
-    KillErr(String), // TODO: Check to see if this is used in epmd
-    KillOk(String),  // "OK" is sent if successful
-    StopErr(String), // "NOEXIST" is sent if node doesn't exist
-    StopOk(String),  // "STOPPED" is sent if node is removed
-}
-
-struct Select {
-    pub fd_top: libc::c_int,     // Max file descriptor value + 1
-    pub fd_set: libc::fd_set, // Select read-mask
-}
-
-impl Select {
-    fn new () -> Select {
-        Select {
-            fd_top: 0,
-            fd_set: libc_utils::new_fd_set(),
-        }
-    }
-
-    fn zero_set(&mut self) {
-        libc_utils::select_zero_set(&mut self.fd_set);
-    }
-
-    fn check(&mut self, fd: libc::c_int) -> bool {
-        libc_utils::select_is_set(&mut self.fd_set, fd)
-    }
-
-    fn select(&self, mut set: libc::fd_set) -> Result<usize> {
-        libc_utils::select(&mut set, self.fd_top)
-    }
-
-    fn set_fd<T: AsRawFd>(&mut self, sock: &T) {
-        let fd = get_raw_fd(sock) as libc::c_int;
-        libc_utils::select_fd_set(&mut self.fd_set, fd);
-        if fd >= self.fd_top {
-            self.fd_top = fd + 1;
-        }
-    }
-
-    fn clr_fd<T: AsRawFd>(&mut self, sock: &T) {
-        let fd = get_raw_fd(sock) as libc::c_int;
-        libc_utils::select_fd_clr(&mut self.fd_set, fd);
+pub fn run (
+    mut epmd: Epmd,
+    config: EpmdConfig,
+) -> Result<(), EpmdError> {
+    epmd.log = logger(&config);
+
+    let mut core = EpmdCore::new(epmd, config);
+    let result = listen_sockets(&core.config)
+        .and_then(|listeners| {
+            // `kill` and `systemctl stop` shut us down the same way as a
+            // KILL request does
+            let sig_term = SigTerm::catch()?;
+            serve(&mut core, listeners, None, Some(&sig_term))
+        });
+    if let Err(ref err) = result {
+        error!(core.epmd.log, "{}", err);
     }
+    result
 }
 
-
-
-pub struct EpmdConfig {
-    // -- program flags --
-    pub debug: bool,
-    pub silent: bool,
-    pub is_daemon: bool,
-    pub is_systemd: bool,
-    pub brutal_kill: bool, // Check if needed
-    pub use_ipv6: bool,
-    // -- extra options --
-    pub packet_timeout: Duration,
-    pub delay_accept: usize,
-    pub delay_write: usize,
-    // -- connection properties --
-    pub address: String,
-    pub port: u16,
-}
-
-impl EpmdConfig {
-    pub fn new() -> EpmdConfig {
-        EpmdConfig {
-            // -- program flags --
-            debug: false,
-            silent: false,
-            is_daemon: false,
-            is_systemd: false,
-            brutal_kill: false,
-            use_ipv6: false,
-            // -- extra options --
-            packet_timeout: Duration::new(CLOSE_TIMEOUT, 0),
-            delay_accept: 0,
-            delay_write: 0,
-            // -- connection properties --
-            address: get_address(),
-            port: get_port_number(),
-            // -- currently unused --
-        }
+/// The logger `config` asks for.
+///
+/// A daemon's stderr goes nowhere, so it logs to syslog instead; without
+/// `-d` that's still enough to follow nodes coming and going.
+pub fn logger(config: &EpmdConfig) -> Logger {
+    let level = Level::from_count(config.debug);
+    if config.is_daemon {
+        let level = cmp::max(level, Level::Info);
+        Logger::syslog(level, &config.syslog_path, config.syslog_facility,
+                       &config.syslog_ident)
+            .unwrap_or_else(|_| Logger::stderr(level))
+    } else {
+        Logger::stderr(level)
     }
 }
 
-#[derive(Debug)]
-pub struct Epmd {
-    // -- program data --
-    pub active_conn: usize,
-    pub max_conn: usize,
-    pub nodes: HashSet<ErlNode>,
-}
-
-impl Epmd {
-    pub fn new () -> Epmd {
-        Epmd {
-            active_conn: 0,
-            max_conn: MAX_FILE_DESCRIPTORS,
-            nodes: HashSet::<ErlNode>::new(),
-        }
+/// The listening sockets `config` asks for; either those passed to us by
+/// systemd or bound to the configured addresses and port.
+pub fn listen_sockets(config: &EpmdConfig) -> Result<Vec<TcpListener>, EpmdError> {
+    // Under systemd socket activation the sockets come ready-bound; see [2]
+    if config.is_systemd {
+        return systemd::listen_fds(config.port);
     }
-}
-
-
-pub fn run (
-    mut epmd: Epmd,
-    config: EpmdConfig,
-) {
-    println!("");
-    /* TODO: systemd related initialization...
-    epmd does some querying of the system though systemd if it's available.
-    namely using `sd_listen_fds(0)` To get the max # of sockets of the
-    system. [2] has a decent way to go about using systemd to find the
-    start of the socket counters in this way. This is set elsewhere so I'll
-    just ignore this for now. ( the max will just be `MAX_FILE_DESCRIPTORS` )
-
-    IDEA?: maybe put all of the systemd functions into it's own module?
-     */
-
     let addrs =
         parse_socket_addrs(
             &config.address,
             config.port,
             config.use_ipv6);
 
-    let num_sockets = addrs.len();
-
-    if num_sockets >= MAX_LISTEN_SOCKETS {
-        panic!("Cannot listen on more than {} IP Addresses",
-               MAX_LISTEN_SOCKETS);
+    if addrs.len() >= MAX_LISTEN_SOCKETS {
+        return Err(EpmdError::TooManyAddresses(addrs.len()));
     }
 
+    create_listen_sockets(addrs)
+}
+
+/// Serves on `listeners` until we're killed, told to stop through `control`
+/// when we're embedded in another program, or sent a SIGTERM we're
+/// catching with `sig_term`.
+pub fn serve (
+    core: &mut EpmdCore,
+    listeners: Vec<TcpListener>,
+    control: Option<&Control>,
+    sig_term: Option<&SigTerm>,
+) -> Result<(), EpmdError> {
+    let num_sockets = listeners.len();
+
     if cfg!(all(unix)) {
         libc_utils::ignore_sig_pipe();
     }
 
+    // Debugging is easier with fewer old names hanging around
+    if core.config.debug > 0 {
+        core.epmd.nodes.set_max_unreg(DEBUG_MAX_UNREG_COUNT);
+    }
+
+    // `Poll` has no `FD_SETSIZE` limit, we're only bound by how many files
+    // the system lets us open.
+    if let Some(max_files) = libc_utils::max_open_files() {
+        core.epmd.max_conn = max_files;
+    }
+
     // Initialize the number of active file descriptors;
     // `stdin`, `stdout`, & `stderr` are still open.
-    epmd.active_conn = 3 + num_sockets;
-    epmd.max_conn -= num_sockets;
+    core.epmd.active_conn = 3 + num_sockets;
+    core.epmd.max_conn = core.epmd.max_conn.saturating_sub(num_sockets);
 
-    let listeners = create_listen_sockets(addrs);
-
-    // configure sockets for select()
-    let mut select = Select::new();
+    let poll = Poll::new().map_err(EpmdError::Poll)?;
     for sock in listeners.iter() {
-        println!("DEBUG: Creating set_fd for {:?}", sock);
-        select.set_fd(sock);
-        sock.set_nonblocking(true).expect("sock.set_nonblocking()");
+        poll.add(get_raw_fd(sock)).map_err(EpmdError::Poll)?;
+        sock.set_nonblocking(true)?;
+    }
+    if let Some(control) = control {
+        poll.add(control.fd()).map_err(EpmdError::Poll)?;
+        core.epmd.active_conn += 1;
+    }
+    if let Some(sig_term) = sig_term {
+        poll.add(sig_term.fd()).map_err(EpmdError::Poll)?;
+        core.epmd.active_conn += 1;
     }
 
-    println!("DEBUG: Connected on the following Sockets:");
+    info!(core.epmd.log, "epmd running - daemon = {}",
+          core.config.is_daemon as u8);
     for sock in listeners.iter() {
-        println!("\t{:?}", sock);
+        if let Ok(addr) = sock.local_addr() {
+            info!(core.epmd.log, "listening on {}", addr);
+        }
+    }
+
+    // Let systemd know we're up, now that all of the listeners are in place
+    let mut notifier = if core.config.is_systemd {
+        systemd::Notifier::from_env()
+    } else {
+        None
+    };
+    let mut num_nodes = core.epmd.nodes.len();
+    if let Some(ref notifier) = notifier {
+        let _ = notifier.ready();
+        let _ = notifier.status(&node_status(num_nodes));
     }
 
     // main event loop
     // the main loop goes something like this:
-    //  * Read the select mask too see if there is anything to do
-    //  * if there isn't then just busy loop looking for work
-    //  * if there is something to do, then set the current time & try to
-    //    accept() on all sockets with data until we don't have any one
-    //    trying to connect or we've run out of our allowance of
-    //    connection sockets.
-    //  * For all of our connection objects:
-    //    * if we have an open connection then try to `read()` on
-    //      the socket to get data from the client / send a response.
-    //    * or if the connection should be shutdown we'll kill and free the
-    //      connection; this can be due to a timeout or the client closing
-    //      the connection.
-    let mut connections = Vec::<Connection>::new();
+    //  * Wait until one of the sockets is ready to read, or until the next
+    //    deadline; either a connection which could time out or a watchdog
+    //    ping that is due. With nothing to do we sleep for as long as it
+    //    takes.
+    //  * accept() on the listening sockets which are ready.
+    //  * For the connections which are ready; `read()` on the socket and
+    //    hand the data to the core, then carry out what it says; send
+    //    responses & close connections. A connection the client has closed
+    //    is closed on our side too. Replies the socket can't take straight
+    //    away are finished once it's writable, before reading any more.
+    //  * Accept connections and send replies which were held back, once
+    //    they're due.
+    //  * If a deadline has passed, close the connections which have timed
+    //    out and find the next deadline.
+    let mut connections = HashMap::<libc::c_int, Connection>::new();
+    let mut ready = Vec::new();
+    let mut next_timeout: Option<Instant> = None;
+    // The `-delay_accept` & `-delay_write` debug options hold back accepts
+    // and replies without holding up anything else; the delay is the same
+    // for all, so these are in the order they're due.
+    let delay_accept = Duration::from_secs(core.config.delay_accept as u64);
+    let delay_write = Duration::from_secs(core.config.delay_write as u64);
+    let mut delayed_accepts = VecDeque::<(Instant, libc::c_int)>::new();
+    let mut delayed_actions = VecDeque::<(Instant, Vec<Action>)>::new();
+    let mut stop_at: Option<Instant> = None;
     loop {
-        let now = Instant::now();
-        let mut read_mask = select.fd_set.clone();
-
-        println!("DEBUG: {:?}", connections);
-        println!("DEBUG: {:?}", epmd);
+        trace!(core.epmd.log, "{:?}", connections);
+        trace!(core.epmd.log, "{:?}", core.epmd.nodes);
+
+        // Wake up in time for the next watchdog ping, if systemd wants them,
+        // and for whatever is being held back.
+        let mut deadline = next_timeout;
+        let watchdog_due = notifier.as_ref().and_then(|n| n.watchdog_due());
+        let accept_due = delayed_accepts.front().map(|&(due, _)| due);
+        let write_due = delayed_actions.front().map(|&(due, _)| due);
+        for due in [watchdog_due, accept_due, write_due].iter().flatten() {
+            deadline = earliest(deadline, *due);
+        }
+        let timeout =
+            deadline.map(|d| d.saturating_duration_since(Instant::now()));
 
-        let events = select.select(read_mask).expect("Main loop Select()");
-        if events == 0 {
-            libc_utils::select_zero_set(&mut read_mask);
+        poll.wait(&mut ready, timeout).map_err(EpmdError::Poll)?;
+        let now = Instant::now();
+        if let Some(ref mut notifier) = notifier {
+            let _ = notifier.watchdog(now);
         }
 
-        for sock in listeners.iter() {
-            let fd = get_raw_fd(sock);
-            if select.check(fd) {
-                match sock.accept() {
-                    Ok((stream, peer_addr)) => {
-                        println!("DEBUG: Creating new connection object");
-                        println!("DEBUG: stream:    {:?}", stream);
-                        println!("DEBUG: peer_addr: {:?}", peer_addr);
-                        let timeout = Duration::new(0, 500_000_000); // 0.5 sec
-                        select.set_fd(&stream);
-                        let conn = Connection::new(stream, peer_addr, timeout);
-                        connections.push(conn);
+        for &fd in ready.iter() {
+            if let Some(control) = control.filter(|c| c.fd() == fd) {
+                control.handle_commands(&mut core.epmd, connections.len());
+            } else if let Some(sig_term) = sig_term.filter(|s| s.fd() == fd) {
+                if sig_term.received() {
+                    info!(core.epmd.log, "got SIGTERM");
+                    core.epmd.shutdown = true;
+                }
+            } else if let Some(sock) = listeners.iter().find(|l| get_raw_fd(*l) == fd) {
+                if delay_accept > Duration::from_secs(0) {
+                    // Leave the connection waiting in the backlog, and stop
+                    // watching the listener in the meantime.
+                    let _ = poll.delete(fd);
+                    delayed_accepts.push_back((now + delay_accept, fd));
+                } else if accept_conn(core, &poll, sock, &mut connections) {
+                    let timeout = now + core.config.packet_timeout;
+                    next_timeout = earliest(next_timeout, timeout);
+                }
+            } else {
+                let actions = match connections.get_mut(&fd) {
+                    Some(conn) if conn.open && conn.has_output() => {
+                        // There's room for more of the replies queued
+                        send_output(core, &poll, conn, true);
+                        continue;
                     }
-                    Err(err) => {
-                        match err.kind() {
-                            ErrorKind::Interrupted => {},
-                            ErrorKind::WouldBlock => {},
-                            ErrorKind::TimedOut => {},
-                            error_kind => {
-                                // TODO: Recover gracefully...
-                                panic!("socket.accept(): {:?}", error_kind);
-                            }
+                    Some(conn) if conn.open => {
+                        handle_readable(core, &poll, conn)
+                    }
+                    _ => continue,
+                };
+                if actions.is_empty() {
+                    continue;
+                }
+                if delay_write > Duration::from_secs(0) {
+                    // The held back reply counts as activity once it's
+                    // sent, so the connection can't time out waiting for it.
+                    // One which is to be closed after it isn't read from in
+                    // the meantime either.
+                    if let Some(conn) = connections.get_mut(&fd) {
+                        conn.mod_time = now + delay_write;
+                        if actions.contains(&Action::Close(fd)) {
+                            let _ = poll.delete(fd);
+                            conn.paused = true;
                         }
                     }
+                    delayed_actions.push_back((now + delay_write, actions));
+                } else {
+                    apply_actions(core, &poll, &mut connections, actions,
+                                  &mut next_timeout);
                 }
             }
         }
 
-        for mut conn in &mut connections {
-            let has_timed_out = conn.mod_time + config.packet_timeout < now;
-            if conn.open == true {
-                let fd = get_raw_fd(&conn.stream);
-                let is_set = libc_utils::select_is_set(&mut read_mask, fd);
-                if is_set {
-                    let mesg = conn.read();
-                    let request = parse_request(mesg);
-                    println!("DEBUG: Got request: {:?}", request);
-                    let response = process_request(&mut epmd, request);
-                    if response != EpmdResp::None {
-                        println!("DEBUG: Sending response: {:?}", response);
-                        let resp_data = serialize_response(response);
-                        conn.write(resp_data);
-                    }
-                } else if !conn.keep && has_timed_out {
-                    println!("DEBUG: Dropping connection: {:?}", conn);
-                    conn.close();
-                    select.clr_fd(&conn.stream);
-                    conn.stream.shutdown(Shutdown::Both);
+        // Accept the connections held back by `-delay_accept`
+        while delayed_accepts.front().is_some_and(|&(due, _)| due <= now) {
+            let (_, fd) = delayed_accepts.pop_front().expect("not empty");
+            let sock = listeners.iter().find(|l| get_raw_fd(*l) == fd)
+                .expect("one of our listeners");
+            if accept_conn(core, &poll, sock, &mut connections) {
+                let timeout = now + core.config.packet_timeout;
+                next_timeout = earliest(next_timeout, timeout);
+            }
+            poll.add(fd).map_err(EpmdError::Poll)?;
+        }
+        // Send the replies held back by `-delay_write`
+        while delayed_actions.front().is_some_and(|&(due, _)| due <= now) {
+            let (_, actions) = delayed_actions.pop_front().expect("not empty");
+            apply_actions(core, &poll, &mut connections, actions,
+                          &mut next_timeout);
+        }
+
+        // Only connections which could time out set a deadline, so this is
+        // skipped entirely while just registered nodes are connected.
+        if next_timeout.is_some_and(|t| t <= now) {
+            next_timeout = None;
+            for conn in connections.values_mut() {
+                if !conn.open || conn.keep {
+                    continue;
+                }
+                let timeout = conn.mod_time + core.config.packet_timeout;
+                if timeout <= now {
+                    debug!(core.epmd.log, "{}: connection timed out", conn);
+                    close_conn(core, &poll, conn);
+                } else {
+                    next_timeout = earliest(next_timeout, timeout);
                 }
             }
         }
-        // Remove connection we don't want to keep
-        connections.retain(|conn| !conn.can_remove);
+        // Remove the connections which have been closed; dropping them
+        // closes their sockets, and their fds may be reused by the next
+        // accept, so nothing held back for them may be left either.
+        let mut removed = Vec::new();
+        connections.retain(|&fd, conn| {
+            if conn.can_remove {
+                removed.push(fd);
+            }
+            !conn.can_remove
+        });
+        if !removed.is_empty() {
+            core.epmd.active_conn -= removed.len();
+            for (_, actions) in delayed_actions.iter_mut() {
+                actions.retain(|action| !removed.contains(&action.conn()));
+            }
+        }
+
+        if let Some(ref notifier) = notifier {
+            if core.epmd.nodes.len() != num_nodes {
+                num_nodes = core.epmd.nodes.len();
+                let _ = notifier.status(&node_status(num_nodes));
+            }
+        }
+
+        if core.is_shutdown() {
+            if stop_at.is_none() {
+                info!(core.epmd.log, "shutting down");
+                if let Some(ref notifier) = notifier {
+                    let _ = notifier.stopping();
+                }
+                // Whatever `-delay_write` is holding back is still sent once
+                // it's due, the reply to the KILL among it; no new
+                // connections are accepted in the meantime.
+                for sock in listeners.iter() {
+                    let _ = poll.delete(get_raw_fd(sock));
+                }
+                delayed_accepts.clear();
+                stop_at = Some(delayed_actions.back()
+                    .map_or(now, |&(due, _)| due));
+            }
+            if stop_at.is_some_and(|t| t <= now) {
+                break;
+            }
+        }
     }
+
+    for conn in connections.values_mut() {
+        // One last go at sending what the socket hadn't taken yet
+        let _ = conn.flush();
+        conn.close();
+        let _ = conn.stream.shutdown(Shutdown::Both);
+    }
+    Ok(())
 }
 
-fn serialize_response(resp: EpmdResp) -> Vec<u8> {
-    let ser_u16 = |n: u16| -> [u8; 2] {
-        let be = u16::to_be(n);
-        [(be & 0xFF) as u8, (be >> 8 & 0xFF) as u8]
-    };
-    let ser_u32 = |n: u32| -> [u8; 4] {
-        let be = u32::to_be(n);
-        [(be & 0xFF) as u8,
-         (be >> 8 & 0xFF) as u8,
-         (be >> 16 & 0xFF) as u8,
-         (be >> 24 & 0xFF) as u8]
-    };
-    match resp {
-        EpmdResp::None => vec![],
-        EpmdResp::Alive2(result, creation) => {
-            let c = ser_u16(creation);
-            vec![ALIVE2_RESP, result, c[0], c[1]]
-        }
-        EpmdResp::Port2Err(errno) => {
-            vec![PORT2_RESP, errno]
+/// Accepts a connection waiting on `sock`; `true` if there was one
+fn accept_conn(
+    core: &mut EpmdCore,
+    poll: &Poll,
+    sock: &TcpListener,
+    connections: &mut HashMap<libc::c_int, Connection>,
+) -> bool {
+    let (stream, peer_addr) = match sock.accept() {
+        Ok(accepted) => accepted,
+        Err(ref err) if is_transient(err) => return false,
+        Err(err) => {
+            // e.g. we've run out of file descriptors; the client is dropped
+            // but everyone else is served
+            error!(core.epmd.log, "accept() failed: {}", err);
+            return false;
         }
-        EpmdResp::Port2Ok(res, port, n_type, proto, hver, lver, name, ext) => {
-            let pt    = ser_u16(port);
-            let hv    = ser_u16(hver);
-            let lv    = ser_u16(lver);
-            let n_len = ser_u16(name.len() as u16);
-            let e_len = ser_u16(ext.len() as u16);
-            let mut resp = vec![
-                PORT2_RESP, res,
-                pt[0], pt[1],
-                n_type, proto,
-                hv[0], hv[1],
-                lv[0], lv[1],
-                n_len[0], n_len[1]
-            ];
-            resp.extend_from_slice(&name.into_bytes());
-            resp.push(e_len[0]);
-            resp.push(e_len[1]);
-            resp.extend_from_slice(&ext);
-            resp
+    };
+    if core.epmd.active_conn >= core.epmd.max_conn {
+        // Dropping the stream closes it
+        error!(core.epmd.log, "{}: too many open file descriptors; \
+                               connection refused", peer_addr);
+        return false;
+    }
+    let conn = Connection::new(stream, peer_addr)
+        .and_then(|conn| {
+            poll.add(fd_of(&conn))?;
+            Ok(conn)
+        });
+    match conn {
+        Ok(conn) => {
+            debug!(core.epmd.log, "{}: connection accepted", conn);
+            core.on_connect(fd_of(&conn), conn.peer_addr(), conn.local_addr());
+            connections.insert(fd_of(&conn), conn);
+            core.epmd.active_conn += 1;
+            true
         }
-        EpmdResp::Names(epmd_port, name_list) |
-        EpmdResp::Dump(epmd_port, name_list) => {
-            let ep = ser_u32(epmd_port);
-            let mut resp = vec![ep[0], ep[1], ep[2], ep[3]];
-            resp.extend_from_slice(&name_list.into_bytes());
-            resp
+        Err(err) => {
+            // Dropping the stream closes it
+            error!(core.epmd.log, "{}: connection setup failed: {}",
+                   peer_addr, err);
+            false
         }
-        EpmdResp::KillErr(_) => { vec![] },
-        EpmdResp::KillOk(_)  => { vec![79, 75] /* "OK" */ },
-        EpmdResp::StopErr(_) => { vec![78, 79, 69, 88, 73, 83, 84] }, //"NOEXIST"
-        EpmdResp::StopOk(_)  => { vec![83, 84, 79, 80, 80, 69, 68] }, //"STOPPED"
     }
 }
 
-fn parse_request(mesg: Vec<u8>) -> EpmdReq {
-
-    let parse_u16 = |a:u8, b:u8| -> u16 {
-        u16::from_be(a as u16 | (b as u16) << 8)
+/// Reads what has arrived on `conn` and hands it to the core; returns what
+/// the core wants done about it.
+fn handle_readable(
+    core: &mut EpmdCore,
+    poll: &Poll,
+    conn: &mut Connection,
+) -> Vec<Action> {
+    let mut buf = [0; INBUF_SIZE];
+    let bytes_recv = match conn.read(&mut buf) {
+        Ok(bytes_recv) => bytes_recv,
+        Err(ref e) if is_transient(e) => return Vec::new(),
+        Err(e) => {
+            debug!(core.epmd.log, "{}: read() failed: {}", conn, e);
+            0
+        }
     };
+    if bytes_recv == 0 {
+        // EOF or reset; a node's registration goes with the connection it
+        // was made on.
+        debug!(core.epmd.log, "{}: connection closed", conn);
+        close_conn(core, poll, conn);
+        return Vec::new();
+    }
+    trace!(core.epmd.log, "{}: received {} bytes", conn, bytes_recv);
+    core.on_data(fd_of(conn), &buf[..bytes_recv])
+}
 
-    if mesg.len() < 3 { return EpmdReq::None; }
-
-    let (len_v, data ) = mesg.split_at(2);
-    let (req, data )   = data.split_at(1);
-
-    let _len = parse_u16(len_v[0], len_v[1]);
-
-    match req[0] {
-        120 => {
-            if data.len() < 12 { /* min data: 2+1+1+2+2+2+0+2+0 = 12 bytes */
-                EpmdReq::None
-            } else {
-                // start fixed-len header
-                let port      = parse_u16(data[0], data[1]);
-                let node_type = data[2];
-                let protocol  = data[3];
-                let high_ver  = parse_u16(data[4], data[5]);
-                let low_ver   = parse_u16(data[6], data[7]);
-                // end fixed-len header
-                let (_done, data) = data.split_at(8);
-                let (len,   data) = data.split_at(2);
-                // parse name with bound check
-                let _nlen    = parse_u16(len[0], len[1]);
-                let name_len = if _nlen as usize > (data.len() - 2) {
-                    data.len() as u16 - 2
-                } else {
-                    _nlen
+/// Carries out what the core asked for; a connection left to close once
+/// its replies have been sent gets a deadline in `next_timeout`, in case
+/// the peer never reads them.
+fn apply_actions(
+    core: &mut EpmdCore,
+    poll: &Poll,
+    connections: &mut HashMap<libc::c_int, Connection>,
+    actions: Vec<Action>,
+    next_timeout: &mut Option<Instant>,
+) {
+    for action in actions {
+        match action {
+            Action::Write(fd, data) => {
+                let conn = match connections.get_mut(&fd) {
+                    Some(conn) if conn.open => conn,
+                    _ => continue,
                 };
-                let (_name, data) = data.split_at(name_len as usize);
-                let name = from_utf8(_name).unwrap_or("INVALID_NAME");
-                // parse Extra
-                let (_elen, extra) = data.split_at(2);
-
-                EpmdReq::Alive2(port, node_type, protocol, high_ver,
-                                low_ver, name.to_string(), extra.to_owned())
+                if conn.paused {
+                    // Watched again, in case the socket can't take it all
+                    conn.paused = false;
+                    if let Err(err) = poll.add(fd) {
+                        debug!(core.epmd.log, "{}: {}", conn, err);
+                        close_conn(core, poll, conn);
+                        continue;
+                    }
+                }
+                let waiting = conn.has_output();
+                conn.queue(&data);
+                send_output(core, poll, conn, waiting);
             }
-        }
-        122 => {
-            match from_utf8(data) {
-                Ok(name) => EpmdReq::Port2(name.to_string()),
-                Err(_)   => EpmdReq::None
+            Action::Close(fd) => {
+                let conn = match connections.get_mut(&fd) {
+                    Some(conn) if conn.open => conn,
+                    _ => continue,
+                };
+                if conn.has_output() {
+                    conn.keep = false;
+                    conn.closing = true;
+                    let timeout = conn.mod_time + core.config.packet_timeout;
+                    *next_timeout = earliest(*next_timeout, timeout);
+                } else {
+                    close_conn(core, poll, conn);
+                }
             }
-        }
-        110 => EpmdReq::Names,
-        100 => EpmdReq::Dump,
-        107 => EpmdReq::Kill,
-        115 => {
-            match from_utf8(data) {
-                Ok(name) => EpmdReq::Stop(name.to_string()),
-                Err(_)   => EpmdReq::None
+            Action::KeepAlive(fd) => {
+                if let Some(conn) = connections.get_mut(&fd) {
+                    conn.keep = true;
+                }
             }
         }
-        _ => EpmdReq::None
     }
 }
 
-fn process_request(epmd: &mut Epmd, req: EpmdReq) -> EpmdResp {
-    match req {
-        EpmdReq::None => EpmdResp::None,
-        EpmdReq::Alive2(port, n_type, proto, h_ver, l_ver, name, extra) => {
-            let node =
-                ErlNode::new(port, n_type, proto, h_ver, l_ver, name, extra);
-            let creation = node.creation;
-            match epmd.nodes.replace(node) {
-                Some( _ ) => { /* have an old entry here;
-                                  TODO: manage reused nodes. */ },
-                None      => { /* New entry so nothing to replace */ }
-            }
-            EpmdResp::Alive2(0 /* OK */, creation)
+/// Sends what's queued on `conn`. While the socket won't take all of it we
+/// wait for it to be writable rather than read any more requests, so a
+/// client which doesn't read its replies only holds itself up. `waiting`
+/// says whether we already are.
+fn send_output(
+    core: &mut EpmdCore,
+    poll: &Poll,
+    conn: &mut Connection,
+    waiting: bool,
+) {
+    match conn.flush() {
+        Ok(true) if conn.closing => close_conn(core, poll, conn),
+        Ok(true) if waiting => {
+            let _ = poll.modify(fd_of(conn), false);
+        }
+        Ok(true) => {}
+        Ok(false) if !waiting => {
+            trace!(core.epmd.log, "{}: waiting to send the rest", conn);
+            let _ = poll.modify(fd_of(conn), true);
+        }
+        Ok(false) => {}
+        Err(err) => {
+            debug!(core.epmd.log, "{}: write() failed: {}", conn, err);
+            close_conn(core, poll, conn);
         }
-        EpmdReq::Port2(name) => {
-            let _ = name;
-            EpmdResp::None
-        },
-        EpmdReq::Names => {
-            EpmdResp::None
-        },
-        EpmdReq::Dump => {
-            EpmdResp::None
-        },
-        EpmdReq::Kill => {
-            EpmdResp::None
-        },
-        EpmdReq::Stop(name) => {
-            let _ = name;
-            EpmdResp::None
-        },
     }
 }
 
+/// The earlier of `deadline`, if there is one, and `other`
+fn earliest(deadline: Option<Instant>, other: Instant) -> Option<Instant> {
+    Some(deadline.map_or(other, |deadline| cmp::min(deadline, other)))
+}
+
+/// The status line shown by `systemctl status`
+fn node_status(num_nodes: usize) -> String {
+    match num_nodes {
+        1 => "1 node registered".into(),
+        n => format!("{} nodes registered", n),
+    }
+}
+
+/// Closes `conn` and stops watching it for data; a node registered on it
+/// is unregistered.
+fn close_conn(core: &mut EpmdCore, poll: &Poll, conn: &mut Connection) {
+    core.on_close(fd_of(conn));
+    conn.close();
+    let _ = poll.delete(fd_of(conn));
+    let _ = conn.stream.shutdown(Shutdown::Both);
+    conn.can_remove = true;
+}
+
+/// Errors from `read()` which don't mean the connection has gone away
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(),
+             ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn fd_of(conn: &Connection) -> libc::c_int {
+    get_raw_fd(&conn.stream)
+}
+
 #[cfg(target_os = "windows")]
 fn get_raw_fd<T: AsRawSock>(sock: &T) -> libc::c_int {
     sock.as_raw_socket() as libc::c_int
 }
-#[cfg(any(unix))]
+#[cfg(unix)]
 fn get_raw_fd<T: AsRawFd>(sock: &T) -> libc::c_int {
     sock.as_raw_fd() as libc::c_int
 }
@@ -450,9 +537,21 @@ mod tests {
 
     use std::net::{Ipv6Addr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-    use socket::parse_socket_addrs;
-    use socket::get_any_address;
-    use socket::get_loopback_address;
+    use crate::socket::parse_socket_addrs;
+    use crate::socket::get_any_address;
+    use crate::socket::get_loopback_address;
+
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::client::EpmdClient;
+    use crate::config::EpmdConfig;
+    use crate::core::{Epmd, EpmdCore};
+    use crate::error::EpmdError;
+    use crate::test_util::{node, TempSocket};
+
+    use super::{listen_sockets, logger, serve, run};
 
     #[test]
     fn test_parse_socket_addrs_blank () {
@@ -494,6 +593,15 @@ mod tests {
         assert_eq!(res.len(), 1);
     }
 
+    #[test]
+    fn test_parse_socket_addrs_duplicates () {
+        let test_str = "127.0.0.1, 123.123.123.123, 123.123.123.123";
+        let addr1 = SocketAddr::V4(
+            SocketAddrV4::new(Ipv4Addr::new(123,123,123,123), 0x1234));
+        let res = parse_socket_addrs(test_str, 0x1234, false);
+        assert_eq!(res, vec![get_loopback_address(0x1234, false), addr1]);
+    }
+
     #[test]
     fn test_parse_socket_addrs_space_seperators () {
         let test_str = "123.123.123.123 234.234.234.234";
@@ -544,4 +652,84 @@ mod tests {
         assert_eq!(res[1], addr1);
         assert_eq!(res[2], addr2);
     }
+
+    #[test]
+    fn test_run_too_many_addresses () {
+        let mut config = EpmdConfig::new();
+        config.address = (2..18).map(|n| format!("127.0.0.{}", n))
+            .collect::<Vec<_>>().join(",");
+        match run(Epmd::new(), config) {
+            Err(EpmdError::TooManyAddresses(17)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_run_port_in_use () {
+        let listener = ::std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let mut config = EpmdConfig::new();
+        config.address = String::new();
+        config.port = listener.local_addr().unwrap().port();
+        match run(Epmd::new(), config) {
+            Err(EpmdError::Bind(..)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_daemon_logs_to_syslog () {
+        let listener = TempSocket::bind("daemon_log");
+        let timeout = Some(Duration::from_secs(5));
+        listener.socket.set_read_timeout(timeout).unwrap();
+
+        let mut config = EpmdConfig::new();
+        config.address = "127.0.0.1".into();
+        config.port = 0;
+        config.is_daemon = true;
+        config.syslog_path = listener.path.clone();
+        config.syslog_ident = "test_epmd".into();
+        // As `run` does, but with the port picked for us read back
+        let listeners = listen_sockets(&config).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let mut epmd = Epmd::new();
+        epmd.log = logger(&config);
+        let mut core = EpmdCore::new(epmd, config);
+        let server =
+            thread::spawn(move || serve(&mut core, listeners, None, None));
+
+        let client = EpmdClient { port: addr.port(), ..EpmdClient::new() };
+        drop(client.register(&node("foo", 5555)).unwrap());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !client.names().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "foo is still registered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.kill().unwrap());
+        server.join().unwrap().unwrap();
+
+        let mut log = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(len) = listener.socket.recv(&mut buf) {
+            let mesg = String::from_utf8_lossy(&buf[..len]).into_owned();
+            let shutdown = mesg.ends_with("shutting down");
+            log.push(mesg);
+            if shutdown {
+                break;
+            }
+        }
+        let expected = [
+            "epmd running - daemon = 1",
+            &format!("listening on {}", addr),
+            "registered foo at port 5555",
+            "unregistered foo at port 5555",
+            "shutting down",
+        ];
+        for text in expected.iter() {
+            assert!(log.iter().any(|mesg| mesg.contains(text)),
+                    "{:?} not in {:?}", text, log);
+        }
+        // LOG_DAEMON (3 << 3) | LOG_INFO (6)
+        assert!(log.iter().all(|mesg| mesg.starts_with("<30>")), "{:?}", log);
+        assert!(log[0].contains(&format!(" test_epmd[{}]: ", process::id())));
+    }
 }
@@ -1,23 +1,27 @@
 #![allow(dead_code, unused_variables)]
 
-use libc_utils::rand_1_3;
+use libc::c_int;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+use crate::constants::DIST_VSN_32BIT_CREATION;
+use crate::libc_utils::{rand_1_3, rand_creation};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ErlNode {
-    //fd: u32,           // socket in use
-    port: u16,         // port number of erlang node
-    name: String,      // name of the erlang node
-    pub creation: u16, // incremented in the range [1..3] for reused nodes
-    node_type: u8,     // 77u8 = normal erlang node; 72u8 = hidden (c-node)
-    protocol: u8,      // 0 = tcp/ipv4
-    high_version: u16, // 0 = OTP-R3 erts-4.6.x; 1 = OTP-R4 erts-4.7.x
-    low_version: u16,  // see above
-    extra: Vec<u8>,
+    pub fd: c_int,         // socket in use
+    pub port: u16,         // port number of erlang node
+    pub name: String,      // name of the erlang node
+    pub creation: u32,     // changed whenever a name is reused; see below
+    pub node_type: u8,     // 77u8 = normal erlang node; 72u8 = hidden (c-node)
+    pub protocol: u8,      // 0 = tcp/ipv4
+    pub high_version: u16, // 0 = OTP-R3 erts-4.6.x; 1 = OTP-R4 erts-4.7.x
+    pub low_version: u16,  // see above
+    pub extra: Vec<u8>,
 }
 
 impl ErlNode {
+    #[allow(clippy::too_many_arguments)]
     pub fn new (
-        //fd: u32,
+        fd: c_int,
         erl_port: u16,
         node_type: u8,
         protocol: u8,
@@ -27,15 +31,30 @@ impl ErlNode {
         extra: Vec<u8>
     ) -> ErlNode {
         ErlNode {
-            //fd: 0,
+            fd,
             port: erl_port,
-            name: name,
-            creation: rand_1_3(),
-            node_type: node_type,
-            protocol: protocol,
+            name,
+            creation: 0, // Picked when the node registers
+            node_type,
+            protocol,
             high_version: high_vsn,
             low_version: low_vsn,
-            extra: extra
+            extra
         }
     }
+
+    /// `true` if the node expects a 32-bit creation in an `ALIVE2_X_RESP`
+    pub fn wants_x_resp(&self) -> bool {
+        self.high_version >= DIST_VSN_32BIT_CREATION
+    }
+}
+
+/// A random creation for a node registering a name for the first time; one
+/// of the whole 32-bit range if it's `wide`, otherwise in [1..3]
+pub fn random_creation(wide: bool) -> u32 {
+    if wide {
+        rand_creation()
+    } else {
+        rand_1_3() as u32
+    }
 }
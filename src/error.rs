@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use libc::c_int;
+
+use crate::constants::MAX_LISTEN_SOCKETS;
+
+/// Everything that can go wrong while running epmd
+#[derive(Debug)]
+pub enum EpmdError {
+    /// More listen addresses were configured than epmd can listen on
+    TooManyAddresses(usize),
+    /// A listen address couldn't be bound
+    Bind(SocketAddr, io::Error),
+    /// None of the listen addresses could be bound
+    NoListeners,
+    /// `-systemd` was given but systemd didn't pass us any sockets
+    NoListenFds,
+    /// An fd systemd passed isn't a TCP socket listening on our port
+    BadListenFd(c_int, u16),
+    /// Detaching from the terminal to become a daemon failed
+    Daemon(&'static str, io::Error),
+    /// Waiting for activity on the sockets failed
+    Poll(io::Error),
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+impl fmt::Display for EpmdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EpmdError::TooManyAddresses(num) => {
+                write!(f, "cannot listen on {} IP addresses; at most {}",
+                       num, MAX_LISTEN_SOCKETS - 1)
+            }
+            EpmdError::Bind(ref addr, ref err) => {
+                write!(f, "failed to bind socket {}: {}", addr, err)
+            }
+            EpmdError::NoListeners => write!(f, "no address to listen on"),
+            EpmdError::NoListenFds => {
+                write!(f, "no sockets passed by systemd")
+            }
+            EpmdError::BadListenFd(fd, port) => {
+                write!(f, "socket {} from systemd is not a listening TCP \
+                           socket on port {}", fd, port)
+            }
+            EpmdError::Daemon(what, ref err) => {
+                write!(f, "can't become a daemon; {} failed: {}", what, err)
+            }
+            EpmdError::Poll(ref err) => write!(f, "poll failed: {}", err),
+            EpmdError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for EpmdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            EpmdError::Bind(_, ref err) |
+            EpmdError::Daemon(_, ref err) |
+            EpmdError::Poll(ref err) |
+            EpmdError::Io(ref err) => Some(err),
+            EpmdError::TooManyAddresses(_) |
+            EpmdError::NoListeners |
+            EpmdError::NoListenFds |
+            EpmdError::BadListenFd(..) => None,
+        }
+    }
+}
+
+impl From<io::Error> for EpmdError {
+    fn from(err: io::Error) -> EpmdError {
+        EpmdError::Io(err)
+    }
+}
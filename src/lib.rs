@@ -1,46 +1,75 @@
 extern crate libc;
 extern crate net2;
 
+#[macro_use]
+mod log;
 mod constants;
+mod error;
+pub mod codec;
 mod usage;
+mod config;
 mod epmd;
+mod core;
 mod connection;
 mod erl_node;
 mod daemon;
 mod parse_args;
 mod libc_utils;
 mod socket;
+mod poll;
+mod registry;
+mod syslog;
+mod systemd;
+mod client;
+mod server;
+#[cfg(feature = "tokio")]
+mod async_server;
+#[cfg(test)]
+mod test_util;
 
 pub use usage::display_usage;
 pub use parse_args::parse_args;
 pub use parse_args::ParseResponse;
 
-pub use epmd::EpmdReq;
-pub use epmd::Epmd;
-pub use epmd::EpmdConfig;
+pub use error::EpmdError;
+
+pub use codec::EpmdReq;
+pub use codec::EpmdResp;
+pub use config::EpmdConfig;
+pub use core::{Action, ConnId, Epmd, EpmdCore};
+
+pub use client::{EpmdClient, NodeInfo, Registration};
+pub use server::{EpmdServer, EpmdServerBuilder, ServerHandle};
+#[cfg(feature = "tokio")]
+pub use async_server::{AsyncServer, ShutdownHandle};
 
 pub fn run_console (
     epmd: Epmd,
     config: EpmdConfig,
-) {
-    epmd::run(epmd, config);
+) -> Result<(), EpmdError> {
+    epmd::run(epmd, config)
+}
+
+/// Sends an interactive command, like `-names`, to a running epmd and
+/// prints its reply; returns the status the program should exit with.
+pub fn run_call (
+    config: &EpmdConfig,
+    req: EpmdReq,
+) -> i32 {
+    client::call(config, req)
 }
 
 #[cfg(unix)]
-pub fn run_daemon(epmd: Epmd, config: EpmdConfig) {
-    daemon::run_daemon_unix(epmd, config);
+pub fn run_daemon(epmd: Epmd, config: EpmdConfig) -> Result<(), EpmdError> {
+    daemon::run_daemon_unix(epmd, config)
 }
 #[cfg(windows)]
 pub fn run_daemon() {
     daemon::run_daemon_win();
 }
 
-#[allow(dead_code)]
 fn check_relaxed() -> bool {
     use std::env::var;
-    match var("ERL_EPMD_RELAXED_COMMAND_CHECK") {
-        Ok(_)  => true,
-        Err(_) => false
-    }
+    var("ERL_EPMD_RELAXED_COMMAND_CHECK").is_ok()
 }
 
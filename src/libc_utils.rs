@@ -1,19 +1,35 @@
 use std::ptr;
 use std::mem;
-use std::io::Error;
-use std::io::Result;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
 
 use libc::{
     self,
-    c_int,
-    fd_set, select as __select,
-    FD_ZERO, FD_ISSET, FD_SET, FD_CLR,
     signal,
-    timeval, clock_gettime, timespec,
-    CLOCK_MONOTONIC, SIGPIPE, SIG_IGN,
+    c_int,
+    clock_gettime, timespec,
+    CLOCK_MONOTONIC, SIGPIPE, SIGTERM, SIG_ERR, SIG_IGN,
 };
 
-use constants::IDLE_TIMEOUT;
+use crate::constants::IDLE_TIMEOUT;
+
+/// The local time formatted as syslog expects it, e.g. "Oct  7 09:05:01"
+pub fn syslog_timestamp() -> String {
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(),
+                       b"%b %e %H:%M:%S\0".as_ptr() as *const libc::c_char,
+                       &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
 
 /// Generates a random number between 1 and 3
 pub fn rand_1_3() -> u16 {
@@ -24,6 +40,21 @@ pub fn rand_1_3() -> u16 {
     }
 }
 
+/// Generates a random, non-zero, 32-bit creation
+pub fn rand_creation() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    unsafe {
+        let mut time = timespec { tv_sec: 0, tv_nsec: 0 };
+        clock_gettime(CLOCK_MONOTONIC, &mut time);
+        hasher.write_u64(time.tv_sec as u64);
+        hasher.write_u64(time.tv_nsec as u64);
+    }
+    match hasher.finish() as u32 {
+        0 => 1,
+        n => n,
+    }
+}
+
 /// Ignore the SIGPIPE signal that is raised when we call write
 /// twice on a socket closed by the other end.
 pub fn ignore_sig_pipe () {
@@ -32,57 +63,75 @@ pub fn ignore_sig_pipe () {
     }
 }
 
-/// Generates a newly initialized fd_set
-pub fn new_fd_set () -> fd_set {
+// Where the SIGTERM handler writes to; -1 until it's installed
+static SIG_TERM_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sig_term(_: c_int) {
+    // Only async-signal-safe calls in here; if the socket is full there's
+    // a SIGTERM waiting to be noticed already.
+    let fd = SIG_TERM_FD.load(Ordering::Relaxed);
     unsafe {
-        let mut set = mem::uninitialized();
-        FD_ZERO(&mut set);
-        set
+        libc::write(fd, b"T".as_ptr() as *const libc::c_void, 1);
     }
 }
 
-pub fn select_zero_set (set: &mut fd_set) {
-    unsafe { FD_ZERO(set); }
+/// SIGTERM, turned into something the event loop can wait on; the socket
+/// becomes readable once the signal arrives. This replaces the default
+/// action for the whole process, of dying on the spot.
+#[derive(Debug)]
+pub struct SigTerm {
+    recv: UnixStream,
 }
 
-pub fn select_is_set ( set: &mut fd_set, fd: c_int) -> bool {
-    unsafe { FD_ISSET(fd, set) }
-}
+impl SigTerm {
+    pub fn catch() -> io::Result<SigTerm> {
+        let (recv, send) = UnixStream::pair()?;
+        recv.set_nonblocking(true)?;
+        send.set_nonblocking(true)?;
+        // The handler keeps the sending end for as long as the process lives
+        let send = send.into_raw_fd();
+        let installed = SIG_TERM_FD.compare_exchange(
+            -1, send, Ordering::Relaxed, Ordering::Relaxed);
+        if installed.is_err() {
+            // Caught already; a second socket would never hear of it
+            unsafe { libc::close(send); }
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let handler = on_sig_term as extern "C" fn(c_int) as libc::sighandler_t;
+        if unsafe { signal(SIGTERM, handler) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SigTerm { recv })
+    }
 
-pub fn select_fd_set( set: &mut fd_set, fd: c_int) {
-    unsafe { FD_SET(fd, set); }
-}
+    pub fn fd(&self) -> c_int {
+        self.recv.as_raw_fd()
+    }
 
-pub fn select_fd_clr( set: &mut fd_set, fd: c_int) {
-    unsafe { FD_CLR(fd, set); }
+    /// `true` if SIGTERM has arrived since we last asked
+    pub fn received(&self) -> bool {
+        let mut buf = [0; 16];
+        let mut received = false;
+        loop {
+            match (&self.recv).read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => received = true,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        received
+    }
 }
 
-pub fn select ( set: &mut fd_set, fd_top: c_int ) -> Result<usize> {
-    let mut timeout = timeval { tv_sec: IDLE_TIMEOUT, tv_usec: 0 };
-    let events = unsafe {
-        __select(
-            fd_top,
-            set,             /* read  fds */
-            ptr::null_mut(), /* write fds */
-            ptr::null_mut(), /* error fds */
-            &mut timeout)
-    };
-    if events < 0 {
-        let e = Error::last_os_error();
-        match e.raw_os_error().unwrap() {
-            // Just because all of these aren't defined by ErrorKind...
-            libc::EINTR  => { /* interrupted; this is okay */ },
-            libc::EINVAL => { /* timeout;     this is okay */ },
-            _ => {
-                // Can also return the following:
-                // EBADF: invalid fd
-                // EINVAL: can be:
-                //  - invalid timeout specified
-                //  - `fd_limit` is less than 0 or greater than `FD_SETSIZE`
-                //  - One of the fds refers to a STREAM or Multiplexer
-                return Err(e)
-            },
-        }
+/// The most files this process may have open; `None` if there's no limit
+pub fn max_open_files () -> Option<usize> {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return None;
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
     }
-    Ok(events as usize)
+    Some(limit.rlim_cur as usize)
 }
@@ -0,0 +1,157 @@
+//! Leveled logging for the port mapper.
+//!
+//! How much gets logged follows the number of `-d` flags given, as with
+//! upstream epmd. Messages go to stderr when running in a console and to
+//! syslog once we've become a daemon, as by then stderr is `/dev/null`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::syslog::Syslog;
+
+/// How important a log message is; each `-d` flag enables one more level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error, // Always logged
+    Info,  // -d; nodes coming & going, refused requests, startup
+    Debug, // -d -d; connections and the requests made on them
+    Trace, // -d -d -d; everything else
+}
+
+impl Level {
+    /// The level enabled by giving `-d` `count` times
+    pub fn from_count(count: u8) -> Level {
+        match count {
+            0 => Level::Error,
+            1 => Level::Info,
+            2 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Info  => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn syslog_priority(self) -> libc::c_int {
+        match self {
+            Level::Error => libc::LOG_ERR,
+            Level::Info  => libc::LOG_INFO,
+            Level::Debug |
+            Level::Trace => libc::LOG_DEBUG,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Target {
+    Stderr,
+    Syslog(RefCell<Syslog>),
+}
+
+#[derive(Debug)]
+pub struct Logger {
+    level: Level,
+    target: Target,
+}
+
+impl Logger {
+    /// Logs messages up to `level` on stderr
+    pub fn stderr(level: Level) -> Logger {
+        Logger { level, target: Target::Stderr }
+    }
+
+    /// Logs messages up to `level` to the syslog listening on `path`
+    pub fn syslog<P: AsRef<Path>>(
+        level: Level,
+        path: P,
+        facility: libc::c_int,
+        ident: &str,
+    ) -> io::Result<Logger> {
+        let syslog = Syslog::connect(path, facility, ident)?;
+        Ok(Logger { level, target: Target::Syslog(RefCell::new(syslog)) })
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        if !self.enabled(level) {
+            return;
+        }
+        match self.target {
+            Target::Stderr => {
+                let stderr = io::stderr();
+                let _ = writeln!(stderr.lock(), "epmd: [{}] {}",
+                                 level.name(), args);
+            }
+            Target::Syslog(ref syslog) => {
+                let mesg = fmt::format(args);
+                // There's nowhere left to report a failure to log
+                let priority = level.syslog_priority();
+                let _ = syslog.borrow_mut().send(priority, &mesg);
+            }
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger::stderr(Level::Error)
+    }
+}
+
+macro_rules! error {
+    ($log:expr, $($arg:tt)*) => {
+        $log.log($crate::log::Level::Error, format_args!($($arg)*))
+    }
+}
+
+macro_rules! info {
+    ($log:expr, $($arg:tt)*) => {
+        $log.log($crate::log::Level::Info, format_args!($($arg)*))
+    }
+}
+
+macro_rules! debug {
+    ($log:expr, $($arg:tt)*) => {
+        $log.log($crate::log::Level::Debug, format_args!($($arg)*))
+    }
+}
+
+macro_rules! trace {
+    ($log:expr, $($arg:tt)*) => {
+        $log.log($crate::log::Level::Trace, format_args!($($arg)*))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Level, Logger};
+
+    #[test]
+    fn test_level_from_count () {
+        assert_eq!(Level::from_count(0), Level::Error);
+        assert_eq!(Level::from_count(1), Level::Info);
+        assert_eq!(Level::from_count(2), Level::Debug);
+        assert_eq!(Level::from_count(7), Level::Trace);
+    }
+
+    #[test]
+    fn test_enabled () {
+        let log = Logger::stderr(Level::Info);
+        assert!(log.enabled(Level::Error));
+        assert!(log.enabled(Level::Info));
+        assert!(!log.enabled(Level::Debug));
+        assert!(!log.enabled(Level::Trace));
+    }
+}
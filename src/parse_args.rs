@@ -1,55 +1,55 @@
 use std::env;
 use std::time::Duration;
 
-use epmd::EpmdConfig;
-use epmd::EpmdReq;
-use usage::display_usage;
+use crate::config::EpmdConfig;
+use crate::codec::EpmdReq;
 
 pub enum ParseResponse {
     Ok,
-    ShouldExit,
     BadOpt,
     Call(EpmdReq),
 }
 
 pub fn parse_args(config: &mut EpmdConfig) -> ParseResponse {
 
-    let mut argv = env::args();
-    let argc = argv.len();
+    // Skip over the program name
+    let mut argv = env::args().skip(1);
 
-    // TODO: No arguments given == `normal` run?
-    if argc == 1 {
-        return ParseResponse::Ok;
-    }
+    // Interactive commands are only sent off once all of the other options,
+    // like `-port`, have been read; and only one of them may be given.
+    let mut call: Option<EpmdReq> = None;
+    let mut set_call = |req: EpmdReq| -> bool {
+        let is_first = call.is_none();
+        call = Some(req);
+        is_first
+    };
 
     while let Some(arg) = argv.next() {
 
         match arg.as_ref() {
 
-            "-d" => config.debug = true,
-
-            "-debug" => config.debug = true,
+            "-d" | "-debug" => config.debug = config.debug.saturating_add(1),
 
             "-packet_timeout" => {
-                let val: u64 = match argv.next() {
-                    Some(s) => s.parse().expect("packet_timeout value err"),
-                    None => return ParseResponse::BadOpt
+                let val: u64 = match argv.next().map(|s| s.parse()) {
+                    Some(Ok(val)) => val,
+                    _ => return ParseResponse::BadOpt
                 };
                 config.packet_timeout = Duration::new(val, 0);
             },
 
             "-delay_accept" => {
-                let val: usize = match argv.next() {
-                    Some(s) => s.parse().expect("delay_accept value err"),
-                    None => return ParseResponse::BadOpt
+                let val: usize = match argv.next().map(|s| s.parse()) {
+                    Some(Ok(val)) => val,
+                    _ => return ParseResponse::BadOpt
                 };
                 config.delay_accept = val;
             },
 
             "-delay_write" => {
-                let val: usize = match argv.next() {
-                    Some(s) => s.parse().expect("delay_write value err"),
-                    None => return ParseResponse::BadOpt
+                let val: usize = match argv.next().map(|s| s.parse()) {
+                    Some(Ok(val)) => val,
+                    _ => return ParseResponse::BadOpt
                 };
                 config.delay_write = val;
             },
@@ -59,9 +59,7 @@ pub fn parse_args(config: &mut EpmdConfig) -> ParseResponse {
             "-relaxed_command_check" => config.brutal_kill = true,
 
             "-kill" => {
-                if argc == 1 {
-                    return ParseResponse::Call(EpmdReq::Kill)
-                } else {
+                if !set_call(EpmdReq::Kill) {
                     return ParseResponse::BadOpt
                 }
             },
@@ -75,34 +73,28 @@ pub fn parse_args(config: &mut EpmdConfig) -> ParseResponse {
             },
 
             "-port" => {
-                let val: usize = match argv.next() {
-                    Some(s) => s.parse().expect("port value err"),
-                    None => return ParseResponse::BadOpt
+                let val: u16 = match argv.next().map(|s| s.parse()) {
+                    Some(Ok(val)) => val,
+                    _ => return ParseResponse::BadOpt
                 };
-                config.port = val as u16;
+                config.port = val;
             },
 
             "-names" => {
-                if argc == 1 {
-                    return ParseResponse::Call(EpmdReq::Names)
-                } else {
+                if !set_call(EpmdReq::Names) {
                     return ParseResponse::BadOpt
                 }
             },
 
             "-started" => {
                 config.silent = true;
-                if argc == 1 {
-                    return ParseResponse::Call(EpmdReq::Names)
-                } else {
+                if !set_call(EpmdReq::Names) {
                     return ParseResponse::BadOpt
                 }
             },
 
             "-dump" => {
-                if argc == 1 {
-                    return ParseResponse::Call(EpmdReq::Dump)
-                } else {
+                if !set_call(EpmdReq::Dump) {
                     return ParseResponse::BadOpt
                 }
             },
@@ -112,15 +104,20 @@ pub fn parse_args(config: &mut EpmdConfig) -> ParseResponse {
                     Some(s) => s,
                     None => return ParseResponse::BadOpt
                 };
-                return ParseResponse::Call(EpmdReq::Stop(name))
+                if !set_call(EpmdReq::Stop(name)) {
+                    return ParseResponse::BadOpt
+                }
             },
 
             // TODO: Should only be active if the systemd daemon is available
             // apparently it's hiding under the env_var `HAVE_SYSTEMD_DAEMON`???
             "-systemd" => config.is_systemd = true,
 
-            _ => display_usage(),
+            _ => return ParseResponse::BadOpt,
         };
     }
-    ParseResponse::ShouldExit
+    match call {
+        Some(req) => ParseResponse::Call(req),
+        None => ParseResponse::Ok,
+    }
 }
@@ -0,0 +1,239 @@
+//! Waits for sockets to become ready; with Linux's `epoll(7)`, or with
+//! `poll(2)` elsewhere.
+//!
+//! Unlike `select()` there's no limit on how large a file descriptor we can
+//! wait on. With epoll the cost of a wait is in the number of sockets that
+//! are ready rather than the number we're watching.
+
+#[cfg(not(target_os = "linux"))]
+use std::cell::RefCell;
+use std::io;
+use std::time::Duration;
+
+use libc::{self, c_int};
+
+/// The file descriptors we're waiting on for data to read
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Poll {
+    epfd: c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Poll { epfd })
+    }
+
+    /// Starts waiting for `fd` to become readable
+    pub fn add(&self, fd: c_int) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd as u64,
+        };
+        let res = unsafe {
+            libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut event)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Switches `fd` from waiting to read to waiting until it can be
+    /// written to, or back again if `writable` is `false`
+    pub fn modify(&self, fd: c_int, writable: bool) -> io::Result<()> {
+        let events = if writable { libc::EPOLLOUT } else { libc::EPOLLIN };
+        let mut event = libc::epoll_event {
+            events: events as u32,
+            u64: fd as u64,
+        };
+        let res = unsafe {
+            libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, fd, &mut event)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Stops waiting on `fd`
+    pub fn delete(&self, fd: c_int) -> io::Result<()> {
+        // Kernels before 2.6.9 insist on an event, even though it's unused
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        let res = unsafe {
+            libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, &mut event)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits until any of the file descriptors are ready, or `timeout` has
+    /// passed, and fills `ready` with those that are. Without a timeout this
+    /// waits for as long as it takes.
+    pub fn wait(
+        &self,
+        ready: &mut Vec<c_int>,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        const MAX_EVENTS: usize = 256;
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        ready.clear();
+        let num_events = unsafe {
+            libc::epoll_wait(self.epfd, events.as_mut_ptr(),
+                             MAX_EVENTS as c_int, timeout_millis(timeout))
+        };
+        if num_events < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        for event in &events[..num_events as usize] {
+            ready.push(event.u64 as c_int);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Poll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epfd); }
+    }
+}
+
+/// The file descriptors we're waiting on for data to read
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub struct Poll {
+    fds: RefCell<Vec<libc::pollfd>>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        Ok(Poll { fds: RefCell::new(Vec::new()) })
+    }
+
+    /// Starts waiting for `fd` to become readable
+    pub fn add(&self, fd: c_int) -> io::Result<()> {
+        let mut fds = self.fds.borrow_mut();
+        if fds.iter().any(|pollfd| pollfd.fd == fd) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        fds.push(libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+        Ok(())
+    }
+
+    /// Switches `fd` from waiting to read to waiting until it can be
+    /// written to, or back again if `writable` is `false`
+    pub fn modify(&self, fd: c_int, writable: bool) -> io::Result<()> {
+        let mut fds = self.fds.borrow_mut();
+        let pollfd = fds.iter_mut().find(|pollfd| pollfd.fd == fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        pollfd.events = if writable { libc::POLLOUT } else { libc::POLLIN };
+        Ok(())
+    }
+
+    /// Stops waiting on `fd`
+    pub fn delete(&self, fd: c_int) -> io::Result<()> {
+        let mut fds = self.fds.borrow_mut();
+        let pos = fds.iter().position(|pollfd| pollfd.fd == fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        fds.swap_remove(pos);
+        Ok(())
+    }
+
+    /// Waits until any of the file descriptors are ready, or `timeout` has
+    /// passed, and fills `ready` with those that are. Without a timeout this
+    /// waits for as long as it takes.
+    pub fn wait(
+        &self,
+        ready: &mut Vec<c_int>,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        let mut fds = self.fds.borrow_mut();
+        ready.clear();
+        let num_ready = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t,
+                       timeout_millis(timeout))
+        };
+        if num_ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        // Errors & hang ups are reported whatever we asked for, same as
+        // with epoll; reading or writing is what tells us about them.
+        for pollfd in fds.iter().filter(|pollfd| pollfd.revents != 0) {
+            ready.push(pollfd.fd);
+        }
+        Ok(())
+    }
+}
+
+/// `timeout` in milliseconds, or `-1` to wait for as long as it takes
+fn timeout_millis(timeout: Option<Duration>) -> c_int {
+    match timeout {
+        // Round up, so we don't wake up just before a deadline
+        Some(timeout) => {
+            let millis = timeout.as_micros().div_ceil(1000);
+            millis.min(c_int::MAX as u128) as c_int
+        }
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use super::Poll;
+
+    #[test]
+    fn test_wait () {
+        let poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        poll.add(listener.as_raw_fd()).unwrap();
+        let mut ready = Vec::new();
+
+        let start = Instant::now();
+        poll.wait(&mut ready, Some(Duration::from_millis(50))).unwrap();
+        assert!(ready.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        poll.wait(&mut ready, None).unwrap();
+        assert_eq!(ready, vec![listener.as_raw_fd()]);
+
+        let (stream, _) = listener.accept().unwrap();
+        poll.add(stream.as_raw_fd()).unwrap();
+        client.write_all(b"x").unwrap();
+        poll.wait(&mut ready, None).unwrap();
+        assert_eq!(ready, vec![stream.as_raw_fd()]);
+
+        // There's room to write to a new connection straight away
+        poll.modify(stream.as_raw_fd(), true).unwrap();
+        poll.wait(&mut ready, Some(Duration::from_millis(0))).unwrap();
+        assert_eq!(ready, vec![stream.as_raw_fd()]);
+
+        poll.delete(stream.as_raw_fd()).unwrap();
+        poll.wait(&mut ready, Some(Duration::from_millis(0))).unwrap();
+        assert!(ready.is_empty());
+    }
+}
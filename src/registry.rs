@@ -0,0 +1,236 @@
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Values;
+use std::collections::vec_deque::Iter;
+
+use libc::c_int;
+
+use crate::constants::MAX_UNREG_COUNT;
+use crate::erl_node::ErlNode;
+
+/// The registered nodes, indexed by their name, the connection which owns
+/// their registration and the port they listen on.
+///
+/// Nodes that unregister are kept around, up to `max_unreg` of them, so a
+/// node re-registering under the same name gets a different creation than
+/// its previous incarnation.
+#[derive(Debug)]
+pub struct Registry {
+    nodes: HashMap<String, ErlNode>, // name -> node
+    by_fd: HashMap<c_int, String>,   // owning connection -> name
+    by_port: HashMap<u16, String>,   // node's listen port -> name
+    unreg: VecDeque<ErlNode>,        // unregistered nodes; oldest first
+    max_unreg: usize,
+}
+
+impl Registry {
+    pub fn new () -> Registry {
+        Registry {
+            nodes: HashMap::new(),
+            by_fd: HashMap::new(),
+            by_port: HashMap::new(),
+            unreg: VecDeque::new(),
+            max_unreg: MAX_UNREG_COUNT,
+        }
+    }
+
+    /// Sets how many unregistered nodes are remembered
+    pub fn set_max_unreg(&mut self, max_unreg: usize) {
+        self.max_unreg = max_unreg;
+        while self.unreg.len() > self.max_unreg {
+            self.unreg.pop_front();
+        }
+    }
+
+    /// Registers `node` under its name; a name can only be held by one node
+    /// at a time, and a connection can only own one registration, so `node`
+    /// is handed back if the name is already taken or its connection
+    /// already has a node registered on it.
+    pub fn register(&mut self, mut node: ErlNode) -> Result<(), ErlNode> {
+        if self.nodes.contains_key(&node.name)
+            || self.by_fd.contains_key(&node.fd) {
+            return Err(node);
+        }
+        let old = self.unreg.iter().position(|old| old.name == node.name);
+        if let Some(old) = old.and_then(|idx| self.unreg.remove(idx)) {
+            node.creation = next_creation(&node, old.creation);
+        }
+        self.by_fd.insert(node.fd, node.name.clone());
+        self.by_port.insert(node.port, node.name.clone());
+        self.nodes.insert(node.name.clone(), node);
+        Ok(())
+    }
+
+    /// Removes the node registered as `name`, remembering it as unregistered
+    pub fn unregister(&mut self, name: &str) -> Option<ErlNode> {
+        let node = self.nodes.remove(name)?;
+        if self.by_fd.get(&node.fd).map(|n| n == name).unwrap_or(false) {
+            self.by_fd.remove(&node.fd);
+        }
+        if self.by_port.get(&node.port).map(|n| n == name).unwrap_or(false) {
+            self.by_port.remove(&node.port);
+        }
+        if self.max_unreg > 0 {
+            if self.unreg.len() >= self.max_unreg {
+                self.unreg.pop_front();
+            }
+            self.unreg.push_back(node.clone());
+        }
+        Some(node)
+    }
+
+    /// Removes the node whose registration is owned by the connection `fd`
+    pub fn unregister_fd(&mut self, fd: c_int) -> Option<ErlNode> {
+        let name = self.by_fd.get(&fd)?.clone();
+        self.unregister(&name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ErlNode> {
+        self.nodes.get(name)
+    }
+
+    pub fn get_by_fd(&self, fd: c_int) -> Option<&ErlNode> {
+        self.by_fd.get(&fd).and_then(|name| self.nodes.get(name))
+    }
+
+    pub fn get_by_port(&self, port: u16) -> Option<&ErlNode> {
+        self.by_port.get(&port).and_then(|name| self.nodes.get(name))
+    }
+
+    pub fn iter(&self) -> Values<'_, String, ErlNode> {
+        self.nodes.values()
+    }
+
+    /// The recently unregistered nodes; oldest first
+    pub fn unreg(&self) -> Iter<'_, ErlNode> {
+        self.unreg.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+/// The creation for `node` re-registering a name last held with `creation`.
+/// Nodes older than OTP-23 only have room for creations in [1..3], newer
+/// nodes count up through the whole 32-bit range, skipping 0.
+fn next_creation(node: &ErlNode, creation: u32) -> u32 {
+    if node.wants_x_resp() {
+        match creation.wrapping_add(1) {
+            0 => 1,
+            n => n,
+        }
+    } else {
+        creation % 3 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::erl_node::ErlNode;
+
+    use super::Registry;
+
+    fn node(fd: i32, name: &str, port: u16) -> ErlNode {
+        ErlNode::new(fd, port, 77, 0, 5, 5, name.to_string(), vec![])
+    }
+
+    #[test]
+    fn test_register_and_lookup () {
+        let mut reg = Registry::new();
+        assert!(reg.register(node(7, "foo", 5555)).is_ok());
+        assert_eq!(reg.get("foo").map(|n| n.port), Some(5555));
+        assert_eq!(reg.get_by_fd(7).map(|n| n.port), Some(5555));
+        assert_eq!(reg.get_by_port(5555).map(|n| n.fd), Some(7));
+        assert_eq!(reg.len(), 1);
+    }
+
+    #[test]
+    fn test_register_same_name_is_rejected () {
+        let mut reg = Registry::new();
+        let _ = reg.register(node(7, "foo", 5555));
+        let dup = reg.register(node(8, "foo", 6666));
+        assert_eq!(dup.map_err(|n| n.port), Err(6666));
+        assert!(reg.get_by_fd(8).is_none());
+        assert!(reg.get_by_port(6666).is_none());
+        assert_eq!(reg.get("foo").map(|n| n.port), Some(5555));
+        assert_eq!(reg.len(), 1);
+    }
+
+    #[test]
+    fn test_second_register_on_same_fd_is_rejected () {
+        let mut reg = Registry::new();
+        let _ = reg.register(node(7, "foo", 5555));
+        let dup = reg.register(node(7, "bar", 6666));
+        assert_eq!(dup.map_err(|n| n.name), Err("bar".into()));
+        assert!(reg.get("bar").is_none());
+        assert!(reg.get_by_port(6666).is_none());
+        assert_eq!(reg.get_by_fd(7).map(|n| n.port), Some(5555));
+        // Closing the connection unregisters the one node it owns
+        assert!(reg.unregister_fd(7).is_some());
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn test_unregister_fd () {
+        let mut reg = Registry::new();
+        let _ = reg.register(node(7, "foo", 5555));
+        let _ = reg.register(node(8, "bar", 6666));
+        assert_eq!(reg.unregister_fd(7).map(|n| n.name), Some("foo".into()));
+        assert!(reg.unregister_fd(7).is_none());
+        assert!(reg.get("foo").is_none());
+        assert!(reg.get_by_port(5555).is_none());
+        assert_eq!(reg.len(), 1);
+    }
+
+    #[test]
+    fn test_reregister_cycles_creation () {
+        let mut reg = Registry::new();
+        let _ = reg.register(node(7, "foo", 5555));
+        let mut last = reg.get("foo").unwrap().creation;
+        for fd in 8..14 {
+            reg.unregister("foo");
+            let _ = reg.register(node(fd, "foo", 5555));
+            let creation = reg.get("foo").unwrap().creation;
+            assert!(creation != last);
+            assert!((1..=3).contains(&creation));
+            last = creation;
+        }
+        assert_eq!(reg.unreg().count(), 0);
+    }
+
+    #[test]
+    fn test_unreg_is_bounded () {
+        let mut reg = Registry::new();
+        reg.set_max_unreg(5);
+        for fd in 0..8 {
+            let name = format!("node{}", fd);
+            let _ = reg.register(node(fd, &name, 5555));
+            reg.unregister(&name);
+        }
+        let names: Vec<_> = reg.unreg().map(|n| n.name.clone()).collect();
+        assert_eq!(names, vec!["node3", "node4", "node5", "node6", "node7"]);
+    }
+
+    #[test]
+    fn test_reregister_counts_32bit_creation () {
+        let mut reg = Registry::new();
+        let mut foo = node(7, "foo", 5555);
+        foo.high_version = 6;
+        foo.creation = 0xFFFF_FFFF;
+        let _ = reg.register(foo.clone());
+        reg.unregister("foo");
+        let _ = reg.register(foo);
+        assert_eq!(reg.get("foo").unwrap().creation, 1);
+    }
+}
@@ -0,0 +1,528 @@
+//! Running epmd inside another program, e.g. a test suite.
+//!
+//! `EpmdServer::builder()` configures a server without looking at the
+//! environment, binds it and serves on a thread of its own. The returned
+//! `ServerHandle` reports where it's listening, what's registered with it,
+//! and stops it again.
+//!
+//! ```no_run
+//! use re_epmd::EpmdServer;
+//!
+//! let server = EpmdServer::builder()
+//!     .bind("127.0.0.1:0")
+//!     .relaxed(true)
+//!     .spawn()
+//!     .unwrap();
+//! let client = server.client();
+//! assert!(client.names().unwrap().is_empty());
+//! server.shutdown().unwrap();
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::panic;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use libc::c_int;
+
+use crate::client::{EpmdClient, NodeInfo};
+use crate::constants::MAX_LISTEN_SOCKETS;
+use crate::config::EpmdConfig;
+use crate::core::{Epmd, EpmdCore};
+use crate::epmd;
+use crate::error::EpmdError;
+use crate::socket::create_listen_sockets;
+
+/// An epmd to embed in another program
+#[derive(Debug)]
+pub struct EpmdServer;
+
+impl EpmdServer {
+    pub fn builder() -> EpmdServerBuilder {
+        EpmdServerBuilder::new()
+    }
+}
+
+/// Configures an embedded epmd; see `EpmdServer::builder`
+#[derive(Debug)]
+pub struct EpmdServerBuilder {
+    addrs: Vec<SocketAddr>,
+    error: Option<io::Error>, // The first address which didn't resolve
+    relaxed: bool,
+    debug: u8,
+    packet_timeout: Option<Duration>,
+    delay_accept: usize,
+    delay_write: usize,
+}
+
+impl EpmdServerBuilder {
+    fn new() -> EpmdServerBuilder {
+        EpmdServerBuilder {
+            addrs: Vec::new(),
+            error: None,
+            relaxed: false,
+            debug: 0,
+            packet_timeout: None,
+            delay_accept: 0,
+            delay_write: 0,
+        }
+    }
+
+    /// Listens on `addr`, which may be given more than once; port 0 picks
+    /// a free port. Without any, the server listens on `127.0.0.1:0`.
+    pub fn bind<A: ToSocketAddrs>(mut self, addr: A) -> EpmdServerBuilder {
+        match addr.to_socket_addrs() {
+            Ok(addrs) => {
+                for addr in addrs {
+                    if !self.addrs.contains(&addr) {
+                        self.addrs.push(addr);
+                    }
+                }
+            }
+            Err(err) => {
+                self.error.get_or_insert(err);
+            }
+        }
+        self
+    }
+
+    /// Allows `kill` with nodes still registered, and `stop`; as with
+    /// `-relaxed_command_check`
+    pub fn relaxed(mut self, relaxed: bool) -> EpmdServerBuilder {
+        self.relaxed = relaxed;
+        self
+    }
+
+    /// Logs to stderr at the level given by `debug` `-d` flags
+    pub fn debug(mut self, debug: u8) -> EpmdServerBuilder {
+        self.debug = debug;
+        self
+    }
+
+    /// How long a client gets to send its request
+    pub fn packet_timeout(mut self, timeout: Duration) -> EpmdServerBuilder {
+        self.packet_timeout = Some(timeout);
+        self
+    }
+
+    /// Waits this many seconds before accepting a connection, as with
+    /// `-delay_accept`; to act like a busy epmd
+    pub fn delay_accept(mut self, secs: usize) -> EpmdServerBuilder {
+        self.delay_accept = secs;
+        self
+    }
+
+    /// Waits this many seconds before sending a reply, as with
+    /// `-delay_write`
+    pub fn delay_write(mut self, secs: usize) -> EpmdServerBuilder {
+        self.delay_write = secs;
+        self
+    }
+
+    /// Binds the listening sockets and starts serving on a new thread
+    pub fn spawn(self) -> Result<ServerHandle, EpmdError> {
+        if let Some(err) = self.error {
+            return Err(EpmdError::Io(err));
+        }
+        let mut addrs = self.addrs;
+        if addrs.is_empty() {
+            addrs.push(SocketAddr::from(([127, 0, 0, 1], 0)));
+        }
+        if addrs.len() >= MAX_LISTEN_SOCKETS {
+            return Err(EpmdError::TooManyAddresses(addrs.len()));
+        }
+        let listeners = create_listen_sockets(addrs)?;
+        let local_addrs: Vec<SocketAddr> = listeners.iter()
+            .map(|listener| listener.local_addr())
+            .collect::<io::Result<_>>()?;
+
+        // Everything is set explicitly, so nothing in this process's
+        // environment leaks into the server.
+        let config = EpmdConfig {
+            debug: self.debug,
+            silent: false,
+            is_daemon: false,
+            is_systemd: false,
+            brutal_kill: self.relaxed,
+            use_ipv6: false,
+            packet_timeout: self.packet_timeout
+                .unwrap_or(EpmdConfig::new().packet_timeout),
+            delay_accept: self.delay_accept,
+            delay_write: self.delay_write,
+            address: String::new(),
+            port: local_addrs[0].port(),
+            ..EpmdConfig::new()
+        };
+        let mut epmd = Epmd::new();
+        epmd.log = epmd::logger(&config);
+        let mut core = EpmdCore::new(epmd, config);
+
+        let (wake, server_wake) = UnixStream::pair()?;
+        server_wake.set_nonblocking(true)?;
+        let (commands, server_commands) = mpsc::channel();
+        let control = Control { wake: server_wake, commands: server_commands };
+        let thread = thread::Builder::new()
+            .name("epmd".into())
+            .spawn(move || {
+                epmd::serve(&mut core, listeners, Some(&control), None)
+            })?;
+        Ok(ServerHandle { local_addrs, commands, wake, thread: Some(thread) })
+    }
+}
+
+/// A request from a `ServerHandle` to its server
+#[derive(Debug)]
+pub enum Command {
+    Snapshot(mpsc::Sender<Vec<NodeInfo>>),
+    NumConns(mpsc::Sender<usize>),
+    Shutdown,
+}
+
+/// The server's end of a `ServerHandle`. Commands are queued on a channel;
+/// the socket just wakes up the event loop to look at them.
+#[derive(Debug)]
+pub struct Control {
+    wake: UnixStream,
+    commands: mpsc::Receiver<Command>,
+}
+
+impl Control {
+    pub fn fd(&self) -> c_int {
+        self.wake.as_raw_fd()
+    }
+
+    /// Carries out the queued commands; `num_conns` is how many client
+    /// connections are open.
+    pub fn handle_commands(&self, epmd: &mut Epmd, num_conns: usize) {
+        let mut buf = [0; 64];
+        loop {
+            match (&self.wake).read(&mut buf) {
+                // The handle is gone, so nobody is left to stop us
+                Ok(0) => epmd.shutdown = true,
+                Ok(_) => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(_) => {}
+            }
+            break;
+        }
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Snapshot(reply) => {
+                    let mut nodes: Vec<NodeInfo> = epmd.nodes.iter()
+                        .map(|node| NodeInfo {
+                            name: node.name.clone(),
+                            port: node.port,
+                            node_type: node.node_type,
+                            protocol: node.protocol,
+                            high_version: node.high_version,
+                            low_version: node.low_version,
+                            extra: node.extra.clone(),
+                        })
+                        .collect();
+                    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+                    let _ = reply.send(nodes);
+                }
+                Command::NumConns(reply) => {
+                    let _ = reply.send(num_conns);
+                }
+                Command::Shutdown => epmd.shutdown = true,
+            }
+        }
+    }
+}
+
+/// A running embedded epmd; dropping this stops it too
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    commands: mpsc::Sender<Command>,
+    wake: UnixStream,
+    thread: Option<JoinHandle<Result<(), EpmdError>>>,
+}
+
+impl ServerHandle {
+    /// The addresses the server is listening on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// A client for this server, connecting to its first address
+    pub fn client(&self) -> EpmdClient {
+        let addr = self.local_addrs[0];
+        EpmdClient {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            ..EpmdClient::new()
+        }
+    }
+
+    /// The nodes registered right now, sorted by name; empty once the
+    /// server has stopped.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let (reply, nodes) = mpsc::channel();
+        if self.send(Command::Snapshot(reply)).is_err() {
+            return Vec::new();
+        }
+        nodes.recv().unwrap_or_default()
+    }
+
+    /// How many client connections are open, including those of registered
+    /// nodes; `0` once the server has stopped.
+    pub fn num_connections(&self) -> usize {
+        let (reply, num_conns) = mpsc::channel();
+        if self.send(Command::NumConns(reply)).is_err() {
+            return 0;
+        }
+        num_conns.recv().unwrap_or(0)
+    }
+
+    /// Stops the server and waits for its thread to finish; the result is
+    /// that of the server, e.g. an error which stopped it early.
+    pub fn shutdown(mut self) -> Result<(), EpmdError> {
+        self.stop()
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        (&self.wake).write_all(&[0])
+    }
+
+    fn stop(&mut self) -> Result<(), EpmdError> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        // This fails if the server has stopped already, e.g. when killed
+        let _ = self.send(Command::Shutdown);
+        match thread.join() {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            let _ = self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::codec::{encode_request, EpmdReq};
+    use crate::test_util::node;
+
+    use super::EpmdServer;
+
+    #[test]
+    fn test_spawn_and_shutdown () {
+        let server = EpmdServer::builder()
+            .bind("127.0.0.1:0")
+            .spawn()
+            .unwrap();
+        assert_eq!(server.local_addrs().len(), 1);
+        assert_ne!(server.local_addrs()[0].port(), 0);
+        let client = server.client();
+
+        let foo = client.register(&node("foo", 5555)).unwrap();
+        let _bar = client.register(&node("bar", 5556)).unwrap();
+        assert_eq!(server.nodes(), vec![node("bar", 5556), node("foo", 5555)]);
+        drop(foo);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.nodes().len() != 1 {
+            assert!(Instant::now() < deadline, "foo is still registered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Not relaxed; nodes are still alive
+        assert!(!client.kill().unwrap());
+
+        server.shutdown().unwrap();
+        assert!(client.names().is_err());
+    }
+
+    #[test]
+    fn test_servers_in_parallel () {
+        let servers: Vec<_> = (0..4)
+            .map(|_| {
+                EpmdServer::builder()
+                    .bind("127.0.0.1:0")
+                    .relaxed(true)
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for (i, server) in servers.iter().enumerate() {
+            let name = format!("node{}", i);
+            let _reg = server.client().register(&node(&name, 5555)).unwrap();
+            assert_eq!(server.nodes(), vec![node(&name, 5555)]);
+        }
+        for server in servers {
+            let client = server.client();
+            assert!(client.kill().unwrap());
+            // Already stopped by the kill
+            server.shutdown().unwrap();
+            assert!(client.names().is_err());
+        }
+    }
+
+    #[test]
+    fn test_delay_write () {
+        let server = EpmdServer::builder().delay_write(1).spawn().unwrap();
+        let start = Instant::now();
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                let client = server.client();
+                thread::spawn(move || client.names().unwrap())
+            })
+            .collect();
+        // Still serving while the replies are held back
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.nodes(), vec![]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        for client in clients {
+            assert_eq!(client.join().unwrap(), vec![]);
+        }
+        // Both were held back at the same time
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1900), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_delay_write_kill () {
+        let server = EpmdServer::builder().delay_write(1).spawn().unwrap();
+        let client = server.client();
+        let after = server.client();
+        // The reply is held back, but still sent before we shut down
+        let start = Instant::now();
+        let kill = thread::spawn(move || client.kill().unwrap());
+        // Not blocked while waiting to send it
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.nodes(), vec![]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(kill.join().unwrap());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        server.shutdown().unwrap();
+        assert!(after.names().is_err());
+    }
+
+    #[test]
+    fn test_delay_write_ignores_requests_after_reply () {
+        let server = EpmdServer::builder().delay_write(1).spawn().unwrap();
+        let addr = server.local_addrs()[0];
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // Only the first is answered; the connection is closed after it,
+        // even though that's held back.
+        for req in [EpmdReq::Names, EpmdReq::Dump, EpmdReq::Kill] {
+            stream.write_all(&encode_request(&req).unwrap()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        }
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        // The NAMES reply; our port, and no nodes
+        assert_eq!(reply, (addr.port() as u32).to_be_bytes());
+        assert_eq!(server.nodes(), vec![]);
+        assert!(server.client().names().is_ok());
+    }
+
+    #[test]
+    fn test_delay_accept () {
+        let server = EpmdServer::builder().delay_accept(1).spawn().unwrap();
+        let start = Instant::now();
+        let client = server.client();
+        let names = thread::spawn(move || client.names().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.nodes(), vec![]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(names.join().unwrap(), vec![]);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_abandoned_connections_are_reaped () {
+        let server = EpmdServer::builder()
+            .packet_timeout(Duration::from_millis(200))
+            .spawn()
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        let reg = server.client().register(&node("foo", 5555)).unwrap();
+
+        // Some never send anything, some send half a request and some hang
+        // up straight away.
+        let mut idle = Vec::new();
+        for i in 0..300 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            match i % 3 {
+                0 => idle.push(stream),
+                1 => {
+                    stream.write_all(&[0, 10, b'z']).unwrap();
+                    idle.push(stream);
+                }
+                _ => drop(stream),
+            }
+        }
+        for mut stream in idle {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut buf = [0; 1];
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.num_connections() != 1 {
+            assert!(Instant::now() < deadline,
+                    "{} connections left", server.num_connections());
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Registered nodes don't time out
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(server.nodes(), vec![node("foo", 5555)]);
+        assert_eq!(server.num_connections(), 1);
+        drop(reg);
+        while server.num_connections() != 0 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_client_that_never_reads () {
+        let server = EpmdServer::builder().spawn().unwrap();
+        let client = server.client();
+        let reg = client.register(&node("foo", 5555)).unwrap();
+
+        // A registered node pipelines requests without reading any of the
+        // replies, until neither side's buffers can take any more.
+        let mut stream = reg.stream().try_clone().unwrap();
+        stream.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+        let names = encode_request(&EpmdReq::Names).unwrap().repeat(1024);
+        while stream.write_all(&names).is_ok() {}
+
+        // Everyone else is still served
+        let start = Instant::now();
+        assert_eq!(client.names().unwrap(), vec![("foo".to_string(), 5555)]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(server.num_connections(), 1);
+        drop(reg);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_bad_address () {
+        let result = EpmdServer::builder().bind("not an address").spawn();
+        assert!(result.is_err());
+    }
+}
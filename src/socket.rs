@@ -1,10 +1,12 @@
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::net::{TcpListener};
+use std::io::ErrorKind;
 
 use net2::TcpBuilder;
 
-use constants::IPV6_ONLY;
+use crate::constants::IPV6_ONLY;
+use crate::error::EpmdError;
 
 /// `parse_socket_addrs` assumes that the addresses are given in the forms of:
 ///    "192.168.1.1"
@@ -20,9 +22,9 @@ pub fn parse_socket_addrs (
         // Always join the loopback address
         socket_addrs.push(get_loopback_address(port, use_ipv6));
         // Parse the rest of the addresses given to us in the configuration
-        let mut addrs: Vec<_> =
+        let addrs: Vec<_> =
             addr_str
-            .split(|c| c == ',' || c == ' ')
+            .split([',', ' '])
             .filter_map(|addr| {
                 if let Ok(_a) = addr.parse::<Ipv4Addr>() {
                     let _v4 = SocketAddrV4::new(_a, port);
@@ -35,7 +37,12 @@ pub fn parse_socket_addrs (
                 }
             })
             .collect();
-        socket_addrs.append(&mut addrs);
+        // Listening on an address twice would fail to bind
+        for addr in addrs {
+            if !socket_addrs.contains(&addr) {
+                socket_addrs.push(addr);
+            }
+        }
     } else { // just listen on any address...
         //socket_addrs.push(get_loopback_address(port, use_ipv6));
         socket_addrs.push(get_any_address(port, use_ipv6));
@@ -45,29 +52,45 @@ pub fn parse_socket_addrs (
 
 /// Creates a list of listening sockets from which we can call select on and
 /// check for incoming connections.
-// TODO:
-//  * Catch errors we throw away through the `let _ = Result<()>` statements.
-pub fn create_listen_sockets (in_socks: Vec<SocketAddr>) -> Vec<TcpListener> {
-    let sockets =
-        in_socks.iter()
-        .filter_map(|sock| {
-            let builder = match sock.ip() {
-                IpAddr::V4(..) => TcpBuilder::new_v4(),
-                IpAddr::V6(..) => TcpBuilder::new_v6(),
-            };
-            match builder {
-                Ok(b) => { let _ = b.bind(sock); Some(b) },
-                Err(_) => None,
+///
+/// Addresses which don't exist on this host, such as an IPv6 address on a
+/// host without IPv6, are skipped; any other failure to bind is an error.
+pub fn create_listen_sockets (
+    in_socks: Vec<SocketAddr>
+) -> Result<Vec<TcpListener>, EpmdError> {
+    let mut sockets = Vec::new();
+    for sock in in_socks {
+        let builder = match sock.ip() {
+            IpAddr::V4(..) => TcpBuilder::new_v4(),
+            IpAddr::V6(..) => TcpBuilder::new_v6(),
+        };
+        let builder = match builder {
+            Ok(builder) => builder,
+            Err(_) if sock.is_ipv6() => continue,
+            Err(err) => return Err(EpmdError::Bind(sock, err)),
+        };
+        // Socket options have to be set before the `bind()` to take effect
+        let _ = builder.reuse_address(true);
+        if IPV6_ONLY && sock.is_ipv6() {
+            let _ = builder.only_v6(true);
+        }
+        match builder.bind(sock) {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == ErrorKind::AddrNotAvailable => {
+                continue
             }
-        })
-        .map(|b| { let _ = b.reuse_address(true); b })
-        .map(|b| { if IPV6_ONLY { let _ = b.only_v6(true); } b })
-        .filter_map(|b| b.listen(0).ok())
-        .collect();
-    sockets
+            Err(err) => return Err(EpmdError::Bind(sock, err)),
+        }
+        let listener = builder.listen(libc::SOMAXCONN)
+            .map_err(|err| EpmdError::Bind(sock, err))?;
+        sockets.push(listener);
+    }
+    if sockets.is_empty() {
+        return Err(EpmdError::NoListeners);
+    }
+    Ok(sockets)
 }
 
-
 pub fn get_loopback_address(port: u16, use_ipv6: bool) -> SocketAddr {
     if use_ipv6 {
         let _v6 = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
@@ -80,7 +103,7 @@ pub fn get_loopback_address(port: u16, use_ipv6: bool) -> SocketAddr {
     }
 }
 
-fn get_any_address(port: u16, use_ipv6: bool) -> SocketAddr {
+pub fn get_any_address(port: u16, use_ipv6: bool) -> SocketAddr {
     if use_ipv6 {
         let _v6 = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
         let _v6 = SocketAddrV6::new(_v6, port, 0, 0);
@@ -99,10 +122,10 @@ pub fn get_address() -> String {
 
 pub fn get_port_number() -> u16 {
     use std::env::var;
-    use constants::EPMD_PORT_NUMBER;
+    use crate::constants::EPMD_PORT_NUMBER;
     match var("ERL_EPMD_PORT") {
         Ok(val) => {
-            match u16::from_str_radix(&val, 10) {
+            match val.parse::<u16>() {
                 Ok(val) => val,
                 Err(_) => EPMD_PORT_NUMBER
             }
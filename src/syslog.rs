@@ -0,0 +1,93 @@
+//! A minimal syslog client; messages are sent as datagrams to the local
+//! syslog socket, normally `/dev/log`, in the traditional BSD format:
+//!
+//! ```text
+//! <PRI>Mmm dd hh:mm:ss ident[pid]: message
+//! ```
+//!
+//! This doesn't depend on the C library's `openlog()` state, so it keeps
+//! working after the daemon has closed every file descriptor it inherited.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::libc_utils;
+
+/// Where syslog listens on practically every unix
+pub const SYSLOG_PATH: &str = "/dev/log";
+
+#[derive(Debug)]
+pub struct Syslog {
+    socket: UnixDatagram,
+    path: PathBuf,
+    facility: libc::c_int, // e.g. `libc::LOG_DAEMON`
+    ident: String,
+}
+
+impl Syslog {
+    /// Connects to the syslog socket at `path`; messages are tagged with
+    /// `ident` and logged in `facility`.
+    pub fn connect<P: AsRef<Path>>(
+        path: P,
+        facility: libc::c_int,
+        ident: &str,
+    ) -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+        Ok(Syslog {
+            socket,
+            path: path.as_ref().to_path_buf(),
+            facility,
+            ident: ident.to_string(),
+        })
+    }
+
+    /// Sends `mesg` with the given severity, e.g. `libc::LOG_ERR`.
+    /// If syslog has been restarted since we connected, we reconnect once.
+    pub fn send(&mut self, severity: libc::c_int, mesg: &str) -> io::Result<()> {
+        let packet = self.format(severity, mesg);
+        if self.socket.send(packet.as_bytes()).is_ok() {
+            return Ok(());
+        }
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&self.path)?;
+        self.socket = socket;
+        self.socket.send(packet.as_bytes()).map(|_| ())
+    }
+
+    fn format(&self, severity: libc::c_int, mesg: &str) -> String {
+        format!("<{}>{} {}[{}]: {}",
+                self.facility | (severity & libc::LOG_PRIMASK),
+                libc_utils::syslog_timestamp(),
+                self.ident, process::id(), mesg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::process;
+
+    use crate::test_util::TempSocket;
+
+    use super::Syslog;
+
+    #[test]
+    fn test_send () {
+        let listener = TempSocket::bind("syslog");
+
+        let mut syslog = Syslog::connect(&listener.path, libc::LOG_LOCAL0, "test")
+            .unwrap();
+        syslog.send(libc::LOG_ERR, "hello").unwrap();
+
+        let mut buf = [0; 256];
+        let len = listener.socket.recv(&mut buf).unwrap();
+        let mesg = String::from_utf8_lossy(&buf[..len]).into_owned();
+        // LOG_LOCAL0 (16 << 3) | LOG_ERR (3)
+        assert!(mesg.starts_with("<131>"), "{}", mesg);
+        let tag = format!(" test[{}]: hello", process::id());
+        assert!(mesg.ends_with(&tag), "{}", mesg);
+    }
+}
@@ -0,0 +1,232 @@
+//! Support for running under systemd.
+//!
+//! With socket activation systemd binds the listening sockets itself and
+//! hands them to us as open file descriptors, starting at fd 3; `LISTEN_FDS`
+//! says how many there are and `LISTEN_PID` which process they're meant for.
+//! See `sd_listen_fds(3)`.
+//!
+//! Services of `Type=notify` also tell systemd when they're ready, how they
+//! are doing and that they're still alive by sending datagrams to the socket
+//! named by `NOTIFY_SOCKET`. See `sd_notify(3)`.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{self, UnixDatagram};
+use std::time::{Duration, Instant};
+
+use libc::{self, c_int};
+
+use crate::constants::MAX_LISTEN_SOCKETS;
+use crate::error::EpmdError;
+
+/// The first file descriptor systemd passes to us
+pub const SD_LISTEN_FDS_START: c_int = 3;
+
+/// Takes over the listening sockets systemd passed to this process; they all
+/// have to be TCP sockets listening on `port`.
+///
+/// The `LISTEN_*` variables are left in the environment, where changing them
+/// isn't safe with other threads about; anything we might start ignores
+/// them anyway, as `LISTEN_PID` won't be its pid.
+pub fn listen_fds(port: u16) -> Result<Vec<TcpListener>, EpmdError> {
+    let num_fds = match num_listen_fds() {
+        Some(0) | None => return Err(EpmdError::NoListenFds),
+        Some(n) if n >= MAX_LISTEN_SOCKETS => {
+            return Err(EpmdError::TooManyAddresses(n))
+        }
+        Some(n) => n as c_int,
+    };
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + num_fds {
+        if !is_listening_tcp(fd) {
+            return Err(EpmdError::BadListenFd(fd, port));
+        }
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(ref addr) if addr.port() == port => listeners.push(listener),
+            // Dropping `listener` closes it, but we're giving up anyway
+            _ => return Err(EpmdError::BadListenFd(fd, port)),
+        }
+    }
+    Ok(listeners)
+}
+
+/// The number of sockets systemd passed to this process, if it passed any
+fn num_listen_fds() -> Option<usize> {
+    let pid: libc::pid_t = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != unsafe { libc::getpid() } {
+        // Meant for our parent, or some other process
+        return None;
+    }
+    env::var("LISTEN_FDS").ok()?.parse().ok()
+}
+
+/// `true` if `fd` is a listening IPv4 or IPv6 stream socket
+fn is_listening_tcp(fd: c_int) -> bool {
+    let sock_type = getsockopt(fd, libc::SO_TYPE);
+    let listening = getsockopt(fd, libc::SO_ACCEPTCONN);
+    let family = socket_family(fd);
+    sock_type == Some(libc::SOCK_STREAM)
+        && listening == Some(1)
+        && (family == Some(libc::AF_INET) || family == Some(libc::AF_INET6))
+}
+
+/// The address family `fd` is bound with; unlike `SO_DOMAIN`, asking for
+/// its address works everywhere, not just on Linux.
+fn socket_family(fd: c_int) -> Option<c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(fd,
+                          &mut addr as *mut libc::sockaddr_storage
+                              as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if res == 0 { Some(addr.ss_family as c_int) } else { None }
+}
+
+fn getsockopt(fd: c_int, opt: c_int) -> Option<c_int> {
+    let mut val: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, opt,
+                         &mut val as *mut c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    if res == 0 { Some(val) } else { None }
+}
+
+/// Sends service status notifications to systemd
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    watchdog: Option<Duration>, // How often systemd wants to hear from us
+    last_ping: Instant,
+}
+
+impl Notifier {
+    /// Connects to the socket in `NOTIFY_SOCKET`; `None` if systemd isn't
+    /// expecting notifications from us. As with `listen_fds` the environment
+    /// is only read, never changed.
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog = watchdog_usec().map(Duration::from_micros);
+
+        let socket = UnixDatagram::unbound().ok()?;
+        let addr = if let Some(name) = path.strip_prefix('@') {
+            // Linux's abstract socket namespace
+            abstract_addr(name).ok()?
+        } else {
+            net::SocketAddr::from_pathname(&path).ok()?
+        };
+        socket.connect_addr(&addr).ok()?;
+        Some(Notifier { socket, watchdog, last_ping: Instant::now() })
+    }
+
+    /// Sends `state`, one or more newline separated `VARIABLE=value` pairs
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send(state.as_bytes()).map(|_| ())
+    }
+
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// When the next watchdog ping is due; we ping at twice the rate systemd
+    /// asks for, as `sd_watchdog_enabled(3)` recommends.
+    pub fn watchdog_due(&self) -> Option<Instant> {
+        self.watchdog.map(|usec| self.last_ping + usec / 2)
+    }
+
+    /// Pings the watchdog if a ping is due by `now`
+    pub fn watchdog(&mut self, now: Instant) -> io::Result<()> {
+        match self.watchdog_due() {
+            Some(due) if due <= now => {
+                self.last_ping = now;
+                self.notify("WATCHDOG=1")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The watchdog interval systemd set for this process, if any
+fn watchdog_usec() -> Option<u64> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<libc::pid_t>().ok()? != unsafe { libc::getpid() } {
+            return None;
+        }
+    }
+    match env::var("WATCHDOG_USEC").ok()?.parse().ok()? {
+        0 => None,
+        usec => Some(usec),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<net::SocketAddr> {
+    net::SocketAddr::from_abstract_name(name)
+}
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<net::SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "abstract sockets are only supported on linux"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::os::unix::net::UnixDatagram;
+    use std::time::{Duration, Instant};
+
+    use crate::test_util::TempSocket;
+
+    use super::Notifier;
+
+    #[test]
+    fn test_notifier () {
+        let listener = TempSocket::bind("notify");
+        let socket = UnixDatagram::unbound().unwrap();
+        socket.connect(&listener.path).unwrap();
+        let start = Instant::now();
+        let mut notifier = Notifier {
+            socket,
+            watchdog: Some(Duration::from_secs(10)),
+            last_ping: start,
+        };
+
+        let mut buf = [0; 64];
+        let mut recv = || {
+            let len = listener.socket.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+        notifier.ready().unwrap();
+        assert_eq!(recv(), "READY=1");
+        notifier.status("1 node registered").unwrap();
+        assert_eq!(recv(), "STATUS=1 node registered");
+
+        assert_eq!(notifier.watchdog_due(), Some(start + Duration::from_secs(5)));
+        notifier.watchdog(start + Duration::from_secs(1)).unwrap();
+        notifier.watchdog(start + Duration::from_secs(5)).unwrap();
+        assert_eq!(recv(), "WATCHDOG=1");
+        assert_eq!(notifier.watchdog_due(), Some(start + Duration::from_secs(10)));
+
+        notifier.stopping().unwrap();
+        assert_eq!(recv(), "STOPPING=1");
+    }
+}
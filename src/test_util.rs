@@ -0,0 +1,43 @@
+//! Helpers shared by the unit tests; the integration tests under `tests/`
+//! have their own in `tests/common`.
+
+use std::env;
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+
+use crate::client::NodeInfo;
+
+/// A normal erlang node listening on `port`
+pub fn node(name: &str, port: u16) -> NodeInfo {
+    NodeInfo {
+        name: name.into(), port, node_type: 77, protocol: 0,
+        high_version: 6, low_version: 5, extra: vec![],
+    }
+}
+
+/// A datagram socket bound in the temp directory, standing in for syslog's
+/// or systemd's; the socket file is removed again when this is dropped.
+pub struct TempSocket {
+    pub path: PathBuf,
+    pub socket: UnixDatagram,
+}
+
+impl TempSocket {
+    /// Binds a socket file named after `name`, which has to be unique
+    /// among the tests running in this process
+    pub fn bind(name: &str) -> TempSocket {
+        let path = env::temp_dir()
+            .join(format!("re_epmd_{}_{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        TempSocket { path, socket }
+    }
+}
+
+impl Drop for TempSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
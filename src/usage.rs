@@ -1,4 +1,4 @@
-static USAGE_TEXT:&'static str = "
+static USAGE_TEXT: &str = "
 usage:
     Starting the port mapper daemon:
        epmd [-d|-debug] [DbgExtra...] [-address List]
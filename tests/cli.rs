@@ -0,0 +1,115 @@
+//! Runs the epmd binary's interactive commands, `-names`, `-kill` & co,
+//! against an embedded epmd and checks what they print and exit with.
+
+extern crate re_epmd;
+
+use std::net::TcpListener;
+use std::process::Command;
+
+use re_epmd::{EpmdServer, ServerHandle};
+
+mod common;
+
+use common::node;
+
+/// Runs the binary against the epmd on `port`; returns its exit status,
+/// stdout & stderr.
+fn cli(port: u16, args: &[&str]) -> (i32, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_cli"))
+        .arg("-port")
+        .arg(port.to_string())
+        .args(args)
+        .env_remove("ERL_EPMD_ADDRESS")
+        .env_remove("ERL_EPMD_RELAXED_COMMAND_CHECK")
+        .output()
+        .unwrap();
+    (output.status.code().unwrap_or(-1),
+     String::from_utf8_lossy(&output.stdout).into_owned(),
+     String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+fn spawn(relaxed: bool) -> (ServerHandle, u16) {
+    let server = EpmdServer::builder().relaxed(relaxed).spawn().unwrap();
+    let port = server.local_addrs()[0].port();
+    (server, port)
+}
+
+#[test]
+fn test_names () {
+    let (server, port) = spawn(false);
+    let _reg = server.client().register(&node("foo", 5555)).unwrap();
+    let (status, stdout, stderr) = cli(port, &["-names"]);
+    assert_eq!(status, 0, "{}", stderr);
+    assert_eq!(stdout, format!("epmd: up and running on port {} with data:\n\
+                                name foo at port 5555\n", port));
+}
+
+#[test]
+fn test_dump () {
+    let (server, port) = spawn(false);
+    let reg = server.client().register(&node("foo", 5555)).unwrap();
+    drop(reg);
+    let _reg = server.client().register(&node("bar", 6666)).unwrap();
+    let (status, stdout, stderr) = cli(port, &["-dump"]);
+    assert_eq!(status, 0, "{}", stderr);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next().unwrap(),
+               format!("epmd: up and running on port {} with data:", port));
+    assert!(lines.next().unwrap()
+            .starts_with("active name     <bar> at port 6666, fd = "),
+            "{}", stdout);
+    assert!(lines.next().unwrap()
+            .starts_with("old/unused name <foo>, port = 5555, fd = "),
+            "{}", stdout);
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn test_kill () {
+    let (server, port) = spawn(false);
+    let reg = server.client().register(&node("foo", 5555)).unwrap();
+    let (status, stdout, stderr) = cli(port, &["-kill"]);
+    assert_eq!(status, 0, "{}", stderr);
+    assert_eq!(stdout, "Killing not allowed - living nodes in database.\n");
+
+    drop(reg);
+    let (status, stdout, stderr) = cli(port, &["-kill"]);
+    assert_eq!(status, 0, "{}", stderr);
+    assert_eq!(stdout, "Killed\n");
+    assert!(server.client().names().is_err());
+}
+
+#[test]
+fn test_stop () {
+    let (server, port) = spawn(true);
+    let _reg = server.client().register(&node("foo", 5555)).unwrap();
+    let (status, stdout, stderr) = cli(port, &["-stop", "foo"]);
+    assert_eq!(status, 0, "{}", stderr);
+    assert_eq!(stdout, "STOPPED\n");
+    assert_eq!(server.nodes(), vec![]);
+
+    let (status, stdout, stderr) = cli(port, &["-stop", "foo"]);
+    assert_eq!(status, 0, "{}", stderr);
+    assert_eq!(stdout, "NOEXIST\n");
+}
+
+#[test]
+fn test_epmd_not_running () {
+    // Nothing listens on a port that was free a moment ago
+    let port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+    for cmd in ["-names", "-dump", "-kill"] {
+        let (status, stdout, stderr) = cli(port, &[cmd]);
+        assert_eq!(status, 1);
+        assert_eq!(stdout, "");
+        assert_eq!(stderr, "epmd: Cannot connect to local epmd\n");
+    }
+}
+
+#[test]
+fn test_bad_option () {
+    // Only one command at a time
+    let (status, stdout, _) = cli(4369, &["-names", "-kill"]);
+    assert_eq!(status, 1);
+    assert!(stdout.contains("usage:"), "{}", stdout);
+}
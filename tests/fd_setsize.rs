@@ -0,0 +1,61 @@
+//! Serves more connections than `select()` could wait on. Both ends of
+//! every connection live in this process, whose open file limit is raised
+//! for it; this has a test binary of its own so nothing else runs with it.
+
+extern crate libc;
+extern crate re_epmd;
+
+use std::cmp;
+use std::mem;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+
+use re_epmd::EpmdServer;
+
+mod common;
+
+use common::node;
+
+/// Lets this process have at least `min` files open, as far as the hard
+/// limit allows; returns how many it can have now.
+fn raise_open_files(min: libc::rlim_t) -> libc::rlim_t {
+    unsafe {
+        let mut limit: libc::rlimit = mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+            return 0;
+        }
+        let cur = limit.rlim_cur;
+        limit.rlim_cur = cmp::max(cur, cmp::min(limit.rlim_max, min));
+        if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+            return cur;
+        }
+        limit.rlim_cur
+    }
+}
+
+#[test]
+fn test_serves_past_fd_setsize () {
+    // Before the server looks at how many connections it may have. Each
+    // connection takes an fd at both ends, plus a few for the test harness.
+    const CONNS: usize = 1100;
+    let needed = 2 * CONNS as libc::rlim_t + 100;
+    let limit = raise_open_files(4096);
+    if limit < needed {
+        eprintln!("skipping test_serves_past_fd_setsize: needs {} open files \
+                   but RLIMIT_NOFILE only allows {}", needed, limit);
+        return;
+    }
+    let server = EpmdServer::builder().spawn().unwrap();
+    let addr = server.local_addrs()[0];
+
+    let idle: Vec<_> = (0..CONNS)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    let client = server.client();
+    let reg = client.register(&node("foo", 5555)).unwrap();
+    assert!(reg.stream().as_raw_fd() > 1024);
+    assert_eq!(client.names().unwrap(), vec![("foo".into(), 5555)]);
+    drop(reg);
+    drop(idle);
+    server.shutdown().unwrap();
+}
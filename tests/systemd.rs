@@ -0,0 +1,153 @@
+//! Runs the epmd binary the way systemd's socket activation does; with the
+//! listening socket already open as fd 3 and `LISTEN_FDS`/`LISTEN_PID` set,
+//! and a datagram socket standing in for systemd's notification socket.
+
+extern crate libc;
+extern crate re_epmd;
+
+use std::io::Read;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use re_epmd::EpmdClient;
+
+mod common;
+
+use common::{node, TempSocket};
+
+/// Starts epmd with `fd` passed to it as systemd's first socket
+fn spawn_activated(fd: RawFd, port: u16, listen_pid: &str) -> Child {
+    activated_command(fd, port, listen_pid).spawn().unwrap()
+}
+
+fn activated_command(fd: RawFd, port: u16, listen_pid: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    // `LISTEN_PID` has to be the pid of epmd itself; `exec` keeps the pid
+    // of the shell.
+    cmd.arg("-c")
+        .arg(format!("LISTEN_PID={} exec \"$0\" \"$@\"", listen_pid))
+        .arg(env!("CARGO_BIN_EXE_cli"))
+        .arg("-systemd")
+        .arg("-port")
+        .arg(port.to_string())
+        .env("LISTEN_FDS", "1")
+        .stderr(Stdio::piped());
+    unsafe {
+        cmd.pre_exec(move || {
+            if fd != 3 && libc::dup2(fd, 3) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // Sockets are opened close-on-exec; this one has to survive
+            libc::fcntl(3, libc::F_SETFD, 0);
+            Ok(())
+        });
+    }
+    cmd
+}
+
+fn stderr_of(mut child: Child) -> (i32, String) {
+    let status = child.wait().unwrap();
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    (status.code().unwrap_or(-1), stderr)
+}
+
+#[test]
+fn test_socket_activation () {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let child = spawn_activated(listener.as_raw_fd(), port, "$$");
+    // epmd serves on the socket it was given; the listener we still hold
+    // here is the same socket so it's not dropped until epmd is done.
+    thread::sleep(Duration::from_millis(300));
+
+    let client = EpmdClient { port, ..EpmdClient::new() };
+    assert_eq!(client.names().unwrap(), vec![]);
+    assert!(client.kill().unwrap());
+    let (status, stderr) = stderr_of(child);
+    assert_eq!(status, 0, "{}", stderr);
+}
+
+#[test]
+fn test_socket_activation_rejects_udp () {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let child = spawn_activated(socket.as_raw_fd(), port, "$$");
+    let (status, stderr) = stderr_of(child);
+    assert_eq!(status, 1);
+    assert!(stderr.contains("not a listening TCP socket"), "{}", stderr);
+}
+
+#[test]
+fn test_socket_activation_for_another_pid () {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let child = spawn_activated(listener.as_raw_fd(), port, "1");
+    let (status, stderr) = stderr_of(child);
+    assert_eq!(status, 1);
+    assert!(stderr.contains("no sockets passed by systemd"), "{}", stderr);
+}
+
+#[test]
+fn test_notify () {
+    let notify = TempSocket::bind("test_notify");
+    notify.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 256];
+    let mut recv = || {
+        let len = notify.socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let child = activated_command(listener.as_raw_fd(), port, "$$")
+        .env("NOTIFY_SOCKET", &notify.path)
+        .env("WATCHDOG_USEC", "200000")
+        .spawn()
+        .unwrap();
+    assert_eq!(recv(), "READY=1");
+    assert_eq!(recv(), "STATUS=0 nodes registered");
+    // Pinged at least every 100ms, even with nothing else going on
+    assert_eq!(recv(), "WATCHDOG=1");
+    assert_eq!(recv(), "WATCHDOG=1");
+
+    let client = EpmdClient { port, ..EpmdClient::new() };
+    let reg = client.register(&node("foo", 5555)).unwrap();
+    while recv() != "STATUS=1 node registered" {}
+    drop(reg);
+    while recv() != "STATUS=0 nodes registered" {}
+    assert!(client.kill().unwrap());
+    while recv() != "STOPPING=1" {}
+
+    let (status, stderr) = stderr_of(child);
+    assert_eq!(status, 0, "{}", stderr);
+}
+
+#[test]
+fn test_notify_stopping_on_sigterm () {
+    let notify = TempSocket::bind("test_notify_stopping_on_sigterm");
+    notify.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 256];
+    let mut recv = || {
+        let len = notify.socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let child = activated_command(listener.as_raw_fd(), port, "$$")
+        .env("NOTIFY_SOCKET", &notify.path)
+        .spawn()
+        .unwrap();
+    assert_eq!(recv(), "READY=1");
+    // What `systemctl stop` does
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    while recv() != "STOPPING=1" {}
+
+    let (status, stderr) = stderr_of(child);
+    assert_eq!(status, 0, "{}", stderr);
+}